@@ -0,0 +1,81 @@
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::time::Duration;
+
+use super::process_callbacks_for;
+
+// All in nanoseconds.
+static MIN_BUDGET: AtomicU64 = AtomicU64::new(1_000_000);
+static MAX_BUDGET: AtomicU64 = AtomicU64::new(10_000_000);
+static STEP: AtomicU64 = AtomicU64::new(1_000_000);
+static BUDGET: AtomicU64 = AtomicU64::new(1_000_000);
+
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos().min(u64::MAX as u128) as u64
+}
+
+/// Sets the limits for [`process_callbacks_adaptive`]: its budget stays between `min` and `max`, moving by
+/// `step` each time [`report_tick_pressure`] is called. Defaults to between 1 and 10 milliseconds, a millisecond at
+/// a time. Resets the budget to `min`.
+pub fn set_adaptive_budget(min: Duration, max: Duration, step: Duration) {
+    let min = nanos(min);
+    MIN_BUDGET.store(min, Relaxed);
+    MAX_BUDGET.store(nanos(max).max(min), Relaxed);
+    STEP.store(nanos(step), Relaxed);
+    BUDGET.store(min, Relaxed);
+}
+
+/// Tells [`process_callbacks_adaptive`] how the server's doing, ideally once a tick: `hot` means the tick ran
+/// over or came close, so it gets less time, and otherwise it gets more.
+pub fn report_tick_pressure(hot: bool) {
+    let min = MIN_BUDGET.load(Relaxed);
+    let max = MAX_BUDGET.load(Relaxed);
+    let step = STEP.load(Relaxed);
+    let _ = BUDGET.fetch_update(Relaxed, Relaxed, |budget| {
+        Some(if hot {
+            budget.saturating_sub(step).max(min)
+        } else {
+            budget.saturating_add(step).min(max)
+        })
+    });
+}
+
+/// How long [`process_callbacks_adaptive`] will take next time.
+pub fn adaptive_budget() -> Duration {
+    Duration::from_nanos(BUDGET.load(Relaxed))
+}
+
+/// Same as [`process_callbacks_for`], with a time limit that shrinks when the server's been running hot and grows
+/// when there's room, going by [`report_tick_pressure`]. Returns whether there were callbacks left over.
+pub fn process_callbacks_adaptive() -> bool {
+    process_callbacks_for(adaptive_budget())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget_stays_in_bounds() {
+        let ms = Duration::from_millis;
+        set_adaptive_budget(ms(2), ms(5), ms(2));
+        assert_eq!(adaptive_budget(), ms(2));
+        report_tick_pressure(true);
+        assert_eq!(adaptive_budget(), ms(2));
+        report_tick_pressure(false);
+        assert_eq!(adaptive_budget(), ms(4));
+        report_tick_pressure(false);
+        assert_eq!(adaptive_budget(), ms(5));
+        report_tick_pressure(true);
+        assert_eq!(adaptive_budget(), ms(3));
+        report_tick_pressure(true);
+        assert_eq!(adaptive_budget(), ms(2));
+
+        // A max under the min is taken as the min, and a huge step can't wrap around.
+        set_adaptive_budget(ms(5), ms(1), Duration::MAX);
+        report_tick_pressure(false);
+        assert_eq!(adaptive_budget(), ms(5));
+        report_tick_pressure(true);
+        assert_eq!(adaptive_budget(), ms(5));
+        set_adaptive_budget(ms(1), ms(10), ms(1));
+    }
+}
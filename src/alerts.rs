@@ -0,0 +1,77 @@
+use auxtools::*;
+
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering::Relaxed};
+use std::sync::Mutex;
+
+static PRESSURE_PROC: Mutex<Option<String>> = Mutex::new(None);
+// A fraction of the main queue's capacity, stored as f32 bits.
+static THRESHOLD: AtomicU32 = AtomicU32::new(0);
+static OVER: AtomicBool = AtomicBool::new(false);
+
+static DRAINED_PROC: Mutex<Option<String>> = Mutex::new(None);
+static PROGRESS_PROC: Mutex<Option<String>> = Mutex::new(None);
+
+/// Calls the DM proc `name` (e.g. `"/proc/callbacks_backed_up"`) when the main queue gets more than `threshold`
+/// full, as a fraction of its capacity, with how many callbacks are queued and the capacity as arguments.
+/// It's called once each time the queue goes over, not again until it's dropped back under. It's checked when
+/// processing starts, since DM can only be called from the game thread. None, the default, turns it off.
+pub fn set_pressure_alert_proc(name: Option<&str>, threshold: f32) {
+    *PRESSURE_PROC.lock().unwrap() = name.map(str::to_owned);
+    THRESHOLD.store(threshold.to_bits(), Relaxed);
+    OVER.store(false, Relaxed);
+}
+
+/// Calls the DM proc `name` whenever a processing call runs everything that was queued, leaving the queues empty.
+/// Only when there was something to run, so it isn't called over and over while nothing's being submitted.
+/// For things waiting on a batch of callbacks to finish. None, the default, turns it off.
+pub fn set_drained_proc(name: Option<&str>) {
+    *DRAINED_PROC.lock().unwrap() = name.map(str::to_owned);
+}
+
+/// Calls the DM proc `name` with the progress reported by callbacks sent with
+/// [`submit_progress`](super::submit_progress), from 0 to 1, e.g. to fill in a loading bar. None, the default,
+/// turns it off.
+pub fn set_progress_proc(name: Option<&str>) {
+    *PROGRESS_PROC.lock().unwrap() = name.map(str::to_owned);
+}
+
+fn call_alert(name: &str, args: &[&Value]) {
+    match Proc::find(name) {
+        Some(alert) => {
+            let _ = alert.call(args);
+        }
+        None => super::errors::report_warning(&format!(
+            "The callback alert proc {} doesn't exist",
+            name
+        )),
+    }
+}
+
+pub(crate) fn check_pressure(len: usize, capacity: usize) {
+    let name = match PRESSURE_PROC.lock().unwrap().clone() {
+        Some(name) => name,
+        None => return,
+    };
+    let over = len as f32 > f32::from_bits(THRESHOLD.load(Relaxed)) * capacity as f32;
+    if OVER.swap(over, Relaxed) || !over {
+        return;
+    }
+    call_alert(
+        &name,
+        &[&Value::from(len as f32), &Value::from(capacity as f32)],
+    );
+}
+
+pub(crate) fn drained() {
+    let name = DRAINED_PROC.lock().unwrap().clone();
+    if let Some(name) = name {
+        call_alert(&name, &[]);
+    }
+}
+
+pub(crate) fn progress(progress: f32) {
+    let name = PROGRESS_PROC.lock().unwrap().clone();
+    if let Some(name) = name {
+        call_alert(&name, &[&Value::from(progress.clamp(0.0, 1.0))]);
+    }
+}
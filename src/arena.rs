@@ -0,0 +1,93 @@
+use auxtools::{DMResult, Value};
+use bumpalo::Bump;
+
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+use super::submit::{self, SubmitError};
+use super::{
+    begin_processing, handle, lifecycle, run_callback, stack_trace_proc, stats, ErrorReporter,
+    Timer,
+};
+
+/// A callback that gets scratch space to allocate in. Anything it allocates is thrown away at the end of the pass.
+pub type ArenaFunc = Box<dyn Fn(&Bump) -> DMResult + Send + Sync>;
+
+type ArenaChannel = (flume::Sender<ArenaFunc>, flume::Receiver<ArenaFunc>);
+
+static ARENA_CHANNEL: RwLock<Option<ArenaChannel>> = RwLock::new(None);
+
+pub(crate) fn start() {
+    *ARENA_CHANNEL.write().unwrap() = Some(flume::bounded(100000));
+}
+
+pub(crate) fn stop() {
+    *ARENA_CHANNEL.write().unwrap() = None;
+}
+
+fn with_arena_receiver<T>(f: impl FnOnce(&flume::Receiver<ArenaFunc>) -> T) -> T {
+    let receiver = ARENA_CHANNEL.read().unwrap().as_ref().unwrap().1.clone();
+    f(&receiver)
+}
+
+/// Sends off a callback to be run by [`process_callbacks_with_arena`], without blocking if the queue is full.
+/// These have their own queue, and the other processing functions don't touch them. Otherwise it's like
+/// [`submit`](super::submit): it's turned away while shutting down, counts towards the rate limit, and can be
+/// cancelled by generation.
+pub fn submit_arena(
+    func: impl Fn(&Bump) -> DMResult + Send + Sync + 'static,
+) -> Result<(), SubmitError<ArenaFunc>> {
+    let func = submit::check_open::<ArenaFunc>(Box::new(func))?;
+    let sender = match ARENA_CHANNEL.read().unwrap().as_ref() {
+        Some((sender, _)) => sender.clone(),
+        None => return Err(SubmitError::Disconnected(func)),
+    };
+    let func = submit::count_submit(func)?;
+    match sender.try_send(track(func)) {
+        Ok(()) => {
+            lifecycle::submitted();
+            Ok(())
+        }
+        Err(err) => {
+            submit::uncount_submit();
+            Err(err.into())
+        }
+    }
+}
+
+// Like the main queue's tracking, minus the pending counts, since those are checked against the main queue.
+fn track(func: ArenaFunc) -> ArenaFunc {
+    let submitted = Instant::now();
+    let generation = handle::current_generation();
+    Box::new(move |arena| {
+        stats::record_age(submitted.elapsed());
+        if handle::generation_cancelled(generation) {
+            return Ok(Value::null());
+        }
+        func(arena)
+    })
+}
+
+/// Runs callbacks sent with [`submit_arena`] until the time limit's reached, giving each of them `arena` to
+/// allocate in. The arena is reset once they're done, so keep it around between calls and it'll stop
+/// needing to allocate after a while. Returns whether there were callbacks left over.
+pub fn process_callbacks_with_arena(arena: &mut Bump, duration: Duration) -> bool {
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return with_arena_receiver(|receiver| !receiver.is_empty()),
+    };
+    let mut errors = ErrorReporter::new(stack_trace_proc());
+    let timer = Timer::new(duration);
+    let timed_out = with_arena_receiver(|receiver| {
+        let arena: &Bump = arena;
+        for callback in receiver.try_iter() {
+            run_callback(move || callback(arena), &mut errors);
+            if super::submit::take_stop_request() || timer.check() {
+                return true;
+            }
+        }
+        false
+    });
+    arena.reset();
+    timed_out
+}
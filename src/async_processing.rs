@@ -0,0 +1,66 @@
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// A callback that's safe to run off the game thread, for [`submit_pure`]. It has to be `Send`, and since
+/// `Value` isn't, the compiler won't let one that touches BYOND through:
+/// ```compile_fail
+/// # use auxtools::Value;
+/// let value = Value::null();
+/// auxcallback::submit_pure(move || {
+///     let _ = &value;
+///     Ok(())
+/// });
+/// ```
+pub struct PureCallback(Box<dyn Fn() -> Result<(), String> + Send>);
+
+impl PureCallback {
+    pub fn new(func: impl Fn() -> Result<(), String> + Send + 'static) -> Self {
+        Self(Box::new(func))
+    }
+
+    pub fn call(&self) -> Result<(), String> {
+        (self.0)()
+    }
+}
+
+type PureChannel = (flume::Sender<PureCallback>, flume::Receiver<PureCallback>);
+
+// Read from whichever thread the async processing runs on, so it's behind a lock rather than a static mut.
+static PURE_CHANNEL: RwLock<Option<PureChannel>> = RwLock::new(None);
+
+pub(crate) fn start() {
+    *PURE_CHANNEL.write().unwrap() = Some(flume::bounded(100000));
+}
+
+pub(crate) fn stop() {
+    *PURE_CHANNEL.write().unwrap() = None;
+}
+
+/// Sends off a callback to be run by [`process_callbacks_async`], without blocking if the queue is full.
+/// These have their own queue, and the other processing functions don't touch them.
+pub fn submit_pure(
+    func: impl Fn() -> Result<(), String> + Send + 'static,
+) -> Result<(), flume::TrySendError<PureCallback>> {
+    match PURE_CHANNEL.read().unwrap().as_ref() {
+        Some((sender, _)) => sender.try_send(PureCallback::new(func)),
+        None => Err(flume::TrySendError::Disconnected(PureCallback::new(func))),
+    }
+}
+
+/// Processes callbacks sent with [`submit_pure`] as they come in, until the budget runs out, for running on an
+/// async runtime (anything where tokio's timers work) off the game thread. Gives back the error messages of any
+/// that failed. Does nothing if the library isn't loaded.
+pub async fn process_callbacks_async(budget: Duration) -> Vec<String> {
+    let receiver = match PURE_CHANNEL.read().unwrap().as_ref() {
+        Some((_, receiver)) => receiver.clone(),
+        None => return Vec::new(),
+    };
+    let deadline = tokio::time::Instant::now() + budget;
+    let mut errors = Vec::new();
+    while let Ok(Ok(callback)) = tokio::time::timeout_at(deadline, receiver.recv_async()).await {
+        if let Err(e) = callback.call() {
+            errors.push(e);
+        }
+    }
+    errors
+}
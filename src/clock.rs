@@ -0,0 +1,18 @@
+use std::time::Instant;
+
+/// Where the time limited processing functions get the time from. The usual ones use [`SystemClock`];
+/// [`process_callbacks_for_with_clock`](super::process_callbacks_for_with_clock) takes any, so tests can control it.
+pub trait Clock {
+    /// The current time, as far as this clock's concerned.
+    fn now(&self) -> Instant;
+}
+
+/// Plain old [`Instant::now`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+use super::errors::{
+    set_batch_errors, set_error_history, set_quarantine_on_error, set_report_errors,
+};
+use super::quota::set_cpu_quota;
+use super::submit::{
+    set_max_budget_extension, set_max_chained_submits, set_overflow, set_submit_rate_limit,
+};
+use super::watchdog::set_watchdog;
+use super::{set_default_budget, set_max_recursion};
+
+/// All the usual settings in one place, for setting up once when the library loads, e.g.
+/// ```
+/// # use std::time::Duration;
+/// auxcallback::CallbackConfig::new()
+///     .default_budget(Some(Duration::from_millis(5)))
+///     .overflow(true)
+///     .error_history(20)
+///     .apply();
+/// ```
+/// Only what's been given is changed; everything else is left how it was. Each of these is the same as calling its
+/// setter, which are still there for changing things later.
+#[derive(Clone, Debug, Default)]
+#[must_use = "nothing is changed until the config is applied"]
+pub struct CallbackConfig {
+    default_budget: Option<Option<Duration>>,
+    overflow: Option<bool>,
+    submit_rate_limit: Option<Option<usize>>,
+    max_recursion: Option<usize>,
+    max_chained_submits: Option<usize>,
+    max_budget_extension: Option<Duration>,
+    batch_errors: Option<bool>,
+    report_errors: Option<bool>,
+    error_history: Option<usize>,
+    quarantine_on_error: Option<bool>,
+    watchdog: Option<Option<Duration>>,
+    cpu_quota: Option<(Duration, Duration)>,
+}
+
+impl CallbackConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See [`set_default_budget`].
+    pub fn default_budget(mut self, budget: Option<Duration>) -> Self {
+        self.default_budget = Some(budget);
+        self
+    }
+
+    /// See [`set_overflow`].
+    pub fn overflow(mut self, overflow: bool) -> Self {
+        self.overflow = Some(overflow);
+        self
+    }
+
+    /// See [`set_submit_rate_limit`].
+    pub fn submit_rate_limit(mut self, max_per_tick: Option<usize>) -> Self {
+        self.submit_rate_limit = Some(max_per_tick);
+        self
+    }
+
+    /// See [`set_max_recursion`].
+    pub fn max_recursion(mut self, max_depth: usize) -> Self {
+        self.max_recursion = Some(max_depth);
+        self
+    }
+
+    /// See [`set_max_chained_submits`].
+    pub fn max_chained_submits(mut self, max: usize) -> Self {
+        self.max_chained_submits = Some(max);
+        self
+    }
+
+    /// See [`set_max_budget_extension`].
+    pub fn max_budget_extension(mut self, max: Duration) -> Self {
+        self.max_budget_extension = Some(max);
+        self
+    }
+
+    /// See [`set_batch_errors`].
+    pub fn batch_errors(mut self, batch: bool) -> Self {
+        self.batch_errors = Some(batch);
+        self
+    }
+
+    /// See [`set_report_errors`].
+    pub fn report_errors(mut self, report: bool) -> Self {
+        self.report_errors = Some(report);
+        self
+    }
+
+    /// See [`set_error_history`].
+    pub fn error_history(mut self, size: usize) -> Self {
+        self.error_history = Some(size);
+        self
+    }
+
+    /// See [`set_quarantine_on_error`].
+    pub fn quarantine_on_error(mut self, quarantine: bool) -> Self {
+        self.quarantine_on_error = Some(quarantine);
+        self
+    }
+
+    /// See [`set_watchdog`].
+    pub fn watchdog(mut self, limit: Option<Duration>) -> Self {
+        self.watchdog = Some(limit);
+        self
+    }
+
+    /// See [`set_cpu_quota`].
+    pub fn cpu_quota(mut self, max: Duration, per: Duration) -> Self {
+        self.cpu_quota = Some((max, per));
+        self
+    }
+
+    /// Changes everything that's been given.
+    pub fn apply(self) {
+        if let Some(budget) = self.default_budget {
+            set_default_budget(budget);
+        }
+        if let Some(overflow) = self.overflow {
+            set_overflow(overflow);
+        }
+        if let Some(max_per_tick) = self.submit_rate_limit {
+            set_submit_rate_limit(max_per_tick);
+        }
+        if let Some(max_depth) = self.max_recursion {
+            set_max_recursion(max_depth);
+        }
+        if let Some(max) = self.max_chained_submits {
+            set_max_chained_submits(max);
+        }
+        if let Some(max) = self.max_budget_extension {
+            set_max_budget_extension(max);
+        }
+        if let Some(batch) = self.batch_errors {
+            set_batch_errors(batch);
+        }
+        if let Some(report) = self.report_errors {
+            set_report_errors(report);
+        }
+        if let Some(size) = self.error_history {
+            set_error_history(size);
+        }
+        if let Some(quarantine) = self.quarantine_on_error {
+            set_quarantine_on_error(quarantine);
+        }
+        if let Some(limit) = self.watchdog {
+            set_watchdog(limit);
+        }
+        if let Some((max, per)) = self.cpu_quota {
+            set_cpu_quota(max, per);
+        }
+    }
+}
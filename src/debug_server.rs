@@ -0,0 +1,106 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::{channels_open, debug_dump, metrics_text, pending_count, stats};
+
+// The listening thread, and what tells it to stop.
+static SERVER: Mutex<Option<(Arc<AtomicBool>, JoinHandle<()>)>> = Mutex::new(None);
+
+/// Starts a thread listening on `port`, on localhost only, that answers questions about the callbacks one line at
+/// a time, for poking at a live server without going through DM. It knows `depth`, `stats`, `metrics` and `dump`,
+/// which give back the number of queued callbacks, [`stats`], [`metrics_text`] and [`debug_dump`].
+/// Something like `nc localhost <port>` is enough to talk to it. Starting another one stops the one before, and
+/// it's stopped when the library shuts down, letting go of the port.
+pub fn start_debug_server(port: u16) -> io::Result<()> {
+    stop();
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))?;
+    listener.set_nonblocking(true)?;
+    let stopping = Arc::new(AtomicBool::new(false));
+    let thread_stopping = Arc::clone(&stopping);
+    let handle = std::thread::Builder::new().spawn(move || listen(listener, &thread_stopping))?;
+    *SERVER.lock().unwrap() = Some((stopping, handle));
+    Ok(())
+}
+
+// Stops listening and closes every connection, waiting for all of it to finish.
+pub(crate) fn stop() {
+    let server = SERVER.lock().unwrap().take();
+    if let Some((stopping, handle)) = server {
+        stopping.store(true, Relaxed);
+        handle.thread().unpark();
+        let _ = handle.join();
+    }
+}
+
+fn listen(listener: TcpListener, stopping: &AtomicBool) {
+    let mut connections: Vec<(TcpStream, JoinHandle<io::Result<()>>)> = Vec::new();
+    while !stopping.load(Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // Some platforms hand these out nonblocking like the listener, but serve wants to wait for lines.
+                let _ = stream.set_nonblocking(false);
+                if let Ok(closer) = stream.try_clone() {
+                    if let Ok(handle) = std::thread::Builder::new().spawn(move || serve(stream)) {
+                        connections.push((closer, handle));
+                    }
+                }
+            }
+            Err(_) => std::thread::park_timeout(Duration::from_millis(50)),
+        }
+        connections.retain(|(_, handle)| !handle.is_finished());
+    }
+    for (stream, handle) in connections {
+        let _ = stream.shutdown(Shutdown::Both);
+        let _ = handle.join();
+    }
+}
+
+fn serve(stream: TcpStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let response = match line?.trim() {
+            "dump" => debug_dump(),
+            "depth" | "stats" | "metrics" if !channels_open() => "not loaded".to_owned(),
+            "depth" => pending_count().to_string(),
+            "stats" => format!("{:?}", stats()),
+            "metrics" => metrics_text(),
+            other => format!(
+                "unknown command {:?}; try depth, stats, metrics or dump",
+                other
+            ),
+        };
+        writeln!(writer, "{}", response.trim_end())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stopping_lets_go_of_the_port() {
+        // Port 0 can't be used, since it has to be bound again, so find a free one first.
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        start_debug_server(port).unwrap();
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        writeln!(stream, "depth").unwrap();
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line).unwrap();
+        assert_eq!(line, "not loaded\n");
+
+        stop();
+        // The connection's closed along with it.
+        line.clear();
+        assert_eq!(BufReader::new(&stream).read_line(&mut line).unwrap_or(0), 0);
+        TcpListener::bind((Ipv4Addr::LOCALHOST, port)).unwrap();
+    }
+}
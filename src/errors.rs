@@ -0,0 +1,317 @@
+use auxtools::*;
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+thread_local! {
+    static STACK_TRACE: RefCell<Option<Proc>> = const { RefCell::new(None) };
+    // Innermost last. See with_error_handler.
+    static HANDLER_OVERRIDES: RefCell<Vec<Proc>> = const { RefCell::new(Vec::new()) };
+    // The sweep that the outermost processing call going on right now is, for nested ones to share.
+    static CURRENT_SWEEP: Cell<u64> = const { Cell::new(0) };
+}
+
+// Takes its handler back off the stack once the scope's over, even if it panicked.
+struct HandlerOverride;
+
+impl Drop for HandlerOverride {
+    fn drop(&mut self) {
+        HANDLER_OVERRIDES.with(|overrides| overrides.borrow_mut().pop());
+    }
+}
+
+/// Sends callback errors to `handler` instead of `/proc/auxtools_stack_trace` for anything processed during `f`,
+/// then puts things back how they were. These can be nested, and the innermost one wins. Only applies to the
+/// current thread, and processing functions given their own handler still use that.
+pub fn with_error_handler<T>(handler: Proc, f: impl FnOnce() -> T) -> T {
+    HANDLER_OVERRIDES.with(|overrides| overrides.borrow_mut().push(handler));
+    let _override = HandlerOverride;
+    f()
+}
+
+pub(crate) fn handler_override() -> Option<Proc> {
+    HANDLER_OVERRIDES.with(|overrides| overrides.borrow().last().cloned())
+}
+
+static BATCH_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// If set, errors from a processing call are saved up and sent all at once at the end of it to
+/// `/proc/auxtools_stack_trace_batch`, as a list of messages. If that proc doesn't exist, they're sent one at a time to
+/// `/proc/auxtools_stack_trace` just like without batching. Errors that would've gone to some other handler (one
+/// given to a processing function, or from [`with_error_handler`]) are still saved up, but then sent one at a time
+/// to that handler, since the batch proc is only standing in for the default one. Off by default.
+pub fn set_batch_errors(batch: bool) {
+    BATCH_ERRORS.store(batch, Relaxed);
+}
+
+static ERROR_ORDERING: AtomicBool = AtomicBool::new(false);
+
+/// If set, batched errors are sent with the position of the callback that caused them within the processing call,
+/// like `3: message`, so you can still tell what ran before a failure. Off by default.
+pub fn set_error_ordering(preserve: bool) {
+    ERROR_ORDERING.store(preserve, Relaxed);
+}
+
+static SWEEP_IDS: AtomicBool = AtomicBool::new(false);
+static SWEEPS: AtomicU64 = AtomicU64::new(0);
+
+/// If set, every error message starts with a number for the processing call it came from, like
+/// `[sweep#1234] message`, so everything that went wrong in one bad tick can be picked out together. Processing
+/// calls made from inside callbacks use the same number as the one they're in. Off by default.
+pub fn set_sweep_ids(enabled: bool) {
+    SWEEP_IDS.store(enabled, Relaxed);
+}
+
+static REPORT_ERRORS: AtomicBool = AtomicBool::new(true);
+
+/// If unset, callback errors are only counted in [`error_count`](super::error_count), not sent to DM.
+/// For when there's a lot of errors you already know about drowning everything else out. On by default.
+pub fn set_report_errors(report: bool) {
+    REPORT_ERRORS.store(report, Relaxed);
+}
+
+static ERROR_HISTORY_SIZE: AtomicUsize = AtomicUsize::new(0);
+static ERROR_HISTORY: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+
+/// Keeps the last `size` callback error messages around for [`recent_errors`], so you can look at what's been
+/// going wrong without digging through logs. Zero, the default, keeps none.
+pub fn set_error_history(size: usize) {
+    ERROR_HISTORY_SIZE.store(size, Relaxed);
+    let mut history = ERROR_HISTORY.lock().unwrap();
+    while history.len() > size {
+        history.pop_front();
+    }
+}
+
+/// The most recent callback error messages, oldest first. See [`set_error_history`].
+pub fn recent_errors() -> Vec<String> {
+    ERROR_HISTORY.lock().unwrap().iter().cloned().collect()
+}
+
+fn remember_error(message: &str) {
+    let size = ERROR_HISTORY_SIZE.load(Relaxed);
+    if size == 0 {
+        return;
+    }
+    let mut history = ERROR_HISTORY.lock().unwrap();
+    while history.len() >= size {
+        history.pop_front();
+    }
+    history.push_back(message.to_owned());
+}
+
+/// Gives DM a list of the messages from [`recent_errors`]. Like the other hooks, this has to be manually hooked, e.g.
+/// ```
+/// # use auxtools::*;
+/// #[hook("/proc/recent_callback_errors")]
+/// fn _recent_callback_errors() {
+///     auxcallback::recent_errors_hook()
+/// }
+/// ```
+pub fn recent_errors_hook() -> DMResult {
+    let messages = List::new();
+    for message in recent_errors() {
+        messages.append(Value::from_string(message)?);
+    }
+    Ok(Value::from(messages))
+}
+
+static QUARANTINE_ON_ERROR: AtomicBool = AtomicBool::new(false);
+// In milliseconds.
+static QUARANTINE_COOLDOWN: AtomicU64 = AtomicU64::new(10_000);
+static LAST_ERROR: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// If set, a processing call that has any callback errors stops callbacks from being processed at all
+/// until the quarantine cooldown has passed, to avoid hammering something that's broken. Off by default.
+pub fn set_quarantine_on_error(quarantine: bool) {
+    QUARANTINE_ON_ERROR.store(quarantine, Relaxed);
+}
+
+/// How long processing stops for after errors, if [`set_quarantine_on_error`] is on. Defaults to 10 seconds.
+pub fn set_quarantine_cooldown(cooldown: Duration) {
+    QUARANTINE_COOLDOWN.store(cooldown.as_millis() as u64, Relaxed);
+}
+
+pub(crate) fn quarantined() -> bool {
+    QUARANTINE_ON_ERROR.load(Relaxed)
+        && LAST_ERROR.lock().unwrap().is_some_and(|last| {
+            last.elapsed() < Duration::from_millis(QUARANTINE_COOLDOWN.load(Relaxed))
+        })
+}
+
+/// Finds `/proc/auxtools_stack_trace`, which callback errors get sent to, and keeps it around
+/// so it doesn't have to be looked up again.
+pub(crate) fn find_stack_trace() -> Option<Proc> {
+    STACK_TRACE.with(|cached| {
+        let mut cached = cached.borrow_mut();
+        if cached.is_none() {
+            *cached = Proc::find("/proc/auxtools_stack_trace");
+        }
+        cached.clone()
+    })
+}
+
+pub(crate) fn forget_stack_trace() {
+    STACK_TRACE.with(|cached| *cached.borrow_mut() = None);
+}
+
+/// For problems with the processing itself rather than a particular callback. Goes straight to the stack trace proc.
+pub(crate) fn report_warning(message: &str) {
+    #[cfg(feature = "tracing")]
+    tracing::warn!("{}", message);
+    if let Some(stack_trace) = find_stack_trace() {
+        let _ = stack_trace.call(&[&Value::from_string(message).unwrap()]);
+    }
+}
+
+/// Sends callback errors off to DM for the duration of a processing call.
+pub(crate) struct ErrorReporter {
+    stack_trace: Proc,
+    batched: Option<Vec<(usize, String)>>,
+    recorded: Option<Vec<(usize, String)>>,
+    callbacks_run: usize,
+    started: Instant,
+    had_errors: bool,
+    sweep: Option<u64>,
+}
+
+impl ErrorReporter {
+    pub(crate) fn new(stack_trace: Proc) -> Self {
+        Self {
+            stack_trace,
+            batched: if BATCH_ERRORS.load(Relaxed) {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            recorded: None,
+            callbacks_run: 0,
+            started: Instant::now(),
+            had_errors: false,
+            sweep: if !SWEEP_IDS.load(Relaxed) {
+                None
+            } else if super::submit::processing_depth() <= 1 {
+                let sweep = SWEEPS.fetch_add(1, Relaxed) + 1;
+                CURRENT_SWEEP.with(|current| current.set(sweep));
+                Some(sweep)
+            } else {
+                Some(CURRENT_SWEEP.with(|current| current.get()))
+            },
+        }
+    }
+
+    /// Also keeps a copy of every error along with its callback's position, for [`take_recorded`](Self::take_recorded).
+    pub(crate) fn recording(mut self) -> Self {
+        self.recorded = Some(Vec::new());
+        self
+    }
+
+    pub(crate) fn take_recorded(&mut self) -> Vec<(usize, String)> {
+        self.recorded.take().unwrap_or_default()
+    }
+
+    /// Called before each callback is run, so errors know which one they came from.
+    pub(crate) fn callback_started(&mut self) {
+        self.callbacks_run += 1;
+    }
+
+    pub(crate) fn report(&mut self, message: String) {
+        let message = match self.sweep {
+            Some(sweep) => format!("[sweep#{}] {}", sweep, message),
+            None => message,
+        };
+        self.had_errors = true;
+        super::stats::record_error();
+        remember_error(&message);
+        #[cfg(feature = "tracing")]
+        tracing::error!(
+            callback = self.callbacks_run.saturating_sub(1),
+            "callback failed: {}",
+            message
+        );
+        let index = self.callbacks_run.saturating_sub(1);
+        if let Some(recorded) = &mut self.recorded {
+            recorded.push((index, message.clone()));
+        }
+        if !REPORT_ERRORS.load(Relaxed) {
+            return;
+        }
+        match &mut self.batched {
+            Some(batch) => batch.push((index, message)),
+            None => self.stack_trace(&message),
+        }
+    }
+
+    // Whether errors are going to /proc/auxtools_stack_trace, rather than a handler someone picked.
+    fn uses_default_handler(&self) -> bool {
+        self.stack_trace.path == "/proc/auxtools_stack_trace"
+    }
+
+    fn stack_trace(&self, message: &str) {
+        let _ = self
+            .stack_trace
+            .call(&[&Value::from_string(message).unwrap()]);
+    }
+}
+
+impl Drop for ErrorReporter {
+    fn drop(&mut self) {
+        super::stats::record_pass(self.callbacks_run, self.started.elapsed());
+        if self.had_errors {
+            *LAST_ERROR.lock().unwrap() = Some(Instant::now());
+        }
+        let batch = match self.batched.take() {
+            Some(batch) if !batch.is_empty() => batch,
+            _ => return,
+        };
+        let ordered = ERROR_ORDERING.load(Relaxed);
+        let batch = batch.into_iter().map(|(index, message)| {
+            if ordered {
+                format!("{}: {}", index, message)
+            } else {
+                message
+            }
+        });
+        let batch_proc = if self.uses_default_handler() {
+            Proc::find("/proc/auxtools_stack_trace_batch")
+        } else {
+            None
+        };
+        match batch_proc {
+            Some(batch_proc) => {
+                let messages = List::new();
+                for message in batch {
+                    messages.append(Value::from_string(message).unwrap());
+                }
+                let _ = batch_proc.call(&[&Value::from(messages)]);
+            }
+            None => {
+                for message in batch {
+                    self.stack_trace(&message);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_history_keeps_the_last_few() {
+        set_error_history(3);
+        for i in 0..5 {
+            remember_error(&i.to_string());
+        }
+        assert_eq!(recent_errors(), ["2", "3", "4"]);
+        set_error_history(2);
+        assert_eq!(recent_errors(), ["3", "4"]);
+        set_error_history(0);
+        remember_error("5");
+        assert!(recent_errors().is_empty());
+    }
+}
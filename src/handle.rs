@@ -0,0 +1,114 @@
+use auxtools::*;
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering::Relaxed},
+    Arc, Mutex, Weak,
+};
+
+use super::submit::{submit, SubmitError};
+
+// Every handle's flag, so they can all be cancelled at once. Dead ones get pruned whenever a new one shows up.
+static HANDLES: Mutex<Vec<Weak<AtomicBool>>> = Mutex::new(Vec::new());
+
+/// Lets you cancel a callback sent with [`submit_cancellable`] before it runs.
+pub struct CallbackHandle {
+    finished: Arc<AtomicBool>,
+}
+
+impl CallbackHandle {
+    /// Stops the callback from running, if it hasn't already. Returns whether it was stopped.
+    pub fn cancel(&self) -> bool {
+        !self.finished.swap(true, Relaxed)
+    }
+
+    /// Whether the callback has run or been cancelled.
+    pub fn is_finished(&self) -> bool {
+        self.finished.load(Relaxed)
+    }
+}
+
+/// Sends off a callback, giving back a handle that can cancel it before it runs.
+pub fn submit_cancellable(
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+) -> Result<CallbackHandle, SubmitError> {
+    let finished = Arc::new(AtomicBool::new(false));
+    let callback_finished = Arc::clone(&finished);
+    submit(move || {
+        if callback_finished.swap(true, Relaxed) {
+            Ok(Value::null())
+        } else {
+            func()
+        }
+    })?;
+    let mut handles = HANDLES.lock().unwrap();
+    handles.retain(|handle| handle.strong_count() > 0);
+    handles.push(Arc::downgrade(&finished));
+    Ok(CallbackHandle { finished })
+}
+
+/// Cancels every callback sent with [`submit_cancellable`] that hasn't run yet, returning how many were cancelled.
+pub fn cancel_all_handles() -> usize {
+    let mut handles = HANDLES.lock().unwrap();
+    let cancelled = handles
+        .drain(..)
+        .filter_map(|handle| handle.upgrade())
+        .filter(|finished| !finished.swap(true, Relaxed))
+        .count();
+    cancelled
+}
+
+/// How many callbacks sent with [`submit_cancellable`] haven't yet run or been cancelled. Handy for spotting leaks.
+pub fn outstanding_handles() -> usize {
+    let mut handles = HANDLES.lock().unwrap();
+    handles.retain(|handle| handle.strong_count() > 0);
+    handles
+        .iter()
+        .filter_map(|handle| handle.upgrade())
+        .filter(|finished| !finished.load(Relaxed))
+        .count()
+}
+
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static CANCELLED_BEFORE: AtomicU64 = AtomicU64::new(0);
+
+/// The generation callbacks submitted right now are stamped with. See [`cancel_before`].
+pub fn current_generation() -> u64 {
+    GENERATION.load(Relaxed)
+}
+
+/// Starts a new generation, returning it. Everything submitted from now on gets that one.
+pub fn advance_generation() -> u64 {
+    GENERATION.fetch_add(1, Relaxed) + 1
+}
+
+/// Cancels every callback submitted with a generation older than `generation` that hasn't run yet, e.g.
+/// `cancel_before(advance_generation())` to throw out everything queued so far. Much cheaper than a handle
+/// per callback when you only ever need to cancel in bulk. Only applies to callbacks sent through this crate's
+/// submit functions.
+pub fn cancel_before(generation: u64) {
+    CANCELLED_BEFORE.fetch_max(generation, Relaxed);
+}
+
+pub(crate) fn generation_cancelled(generation: u64) -> bool {
+    generation < CANCELLED_BEFORE.load(Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancelling_generations() {
+        let old = current_generation();
+        let new = advance_generation();
+        assert_eq!(new, old + 1);
+        assert_eq!(current_generation(), new);
+        assert!(!generation_cancelled(old));
+        cancel_before(new);
+        assert!(generation_cancelled(old));
+        assert!(!generation_cancelled(new));
+        // Cancelling less than has already been cancelled doesn't bring anything back.
+        cancel_before(old);
+        assert!(generation_cancelled(old));
+    }
+}
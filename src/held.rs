@@ -0,0 +1,73 @@
+use auxtools::*;
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+// Values can't be sent between threads, so callbacks that need one hold onto an ID into this instead.
+// Everything in here lives and dies on the game thread.
+thread_local! {
+    static HELD_VALUES: RefCell<HashMap<u64, Value>> = RefCell::new(HashMap::new());
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+pub(crate) fn hold(value: Value) -> u64 {
+    let id = NEXT_ID.with(|next| {
+        let id = next.get();
+        next.set(id.wrapping_add(1));
+        id
+    });
+    HELD_VALUES.with(|held| held.borrow_mut().insert(id, value));
+    id
+}
+
+pub(crate) fn take(id: u64) -> Option<Value> {
+    HELD_VALUES.with(|held| held.borrow_mut().remove(&id))
+}
+
+/// A held value that's let go of when this is dropped, so a callback that's dropped without running doesn't leave
+/// its value behind. Meant to be moved into the callback.
+pub(crate) struct Held(u64);
+
+impl Held {
+    pub(crate) fn new(value: Value) -> Self {
+        Self(hold(value))
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.0
+    }
+
+    pub(crate) fn take(&self) -> Option<Value> {
+        take(self.0)
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        // Callbacks can be dropped off the game thread, where there's nothing held, or while it's shutting down.
+        let _ = HELD_VALUES.try_with(|held| held.borrow_mut().remove(&self.0));
+    }
+}
+
+pub(crate) fn clear() {
+    HELD_VALUES.with(|held| held.borrow_mut().clear())
+}
+
+/// Same idea as QDELETED: a datum whose gc_destroyed is set has been qdel'd and shouldn't be touched.
+/// Anything without that var is assumed fine.
+pub(crate) fn is_deleted(value: &Value) -> bool {
+    value
+        .get("gc_destroyed")
+        .map(|destroyed| destroyed.is_truthy())
+        .unwrap_or(false)
+}
+
+/// Whether a /datum/callback shouldn't be invoked, because either it or the object it calls the proc on has been
+/// qdel'd. Global proc callbacks have no object datum to check, only the callback itself.
+pub(crate) fn callback_is_dead(callback: &Value) -> bool {
+    is_deleted(callback)
+        || callback
+            .get("object")
+            .map(|object| is_deleted(&object))
+            .unwrap_or(false)
+}
@@ -0,0 +1,134 @@
+use auxtools::*;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use super::submit::{submit, SubmitError};
+use super::{held, stats};
+
+// Keys of callbacks that are queued or running.
+static CLAIMED_KEYS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+// Holds a key for as long as it's alive. Put in a callback, that's until it's been run, or dropped without running.
+struct KeyClaim(String);
+
+impl KeyClaim {
+    fn claim(key: &str) -> Option<Self> {
+        let mut claimed = CLAIMED_KEYS.lock().unwrap();
+        if claimed
+            .get_or_insert_with(HashSet::new)
+            .insert(key.to_owned())
+        {
+            Some(Self(key.to_owned()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for KeyClaim {
+    fn drop(&mut self) {
+        if let Some(claimed) = CLAIMED_KEYS.lock().unwrap().as_mut() {
+            claimed.remove(&self.0);
+        }
+    }
+}
+
+/// Sends off a callback unless one with the same key is already queued or still running, in which case you get
+/// [`SubmitError::Duplicate`]. The key stays claimed while the callback runs, so resubmitting the same key from
+/// inside it is rejected too. It's freed up once the callback's finished, or dropped without running.
+pub fn submit_keyed(
+    key: &str,
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+) -> Result<(), SubmitError> {
+    let claim = match KeyClaim::claim(key) {
+        Some(claim) => claim,
+        None => return Err(SubmitError::Duplicate(Box::new(func))),
+    };
+    submit(move || {
+        let _claim = &claim;
+        func()
+    })
+}
+
+/// Sends off a shared callback, unless that same one is already queued or still running, in which case you get
+/// [`SubmitError::Duplicate`]. Callbacks count as the same if they're clones of the same `Arc`, going by the
+/// address it points to with the vtable half of the pointer thrown away, so the same closure made twice is two
+/// different callbacks. For catching code that queues the same work twice by mistake.
+pub fn submit_unique(func: Arc<dyn Fn() -> DMResult + Send + Sync>) -> Result<(), SubmitError> {
+    let key = format!("closure:{:p}", Arc::as_ptr(&func) as *const ());
+    let claim = match KeyClaim::claim(&key) {
+        Some(claim) => claim,
+        None => return Err(SubmitError::Duplicate(Box::new(move || func()))),
+    };
+    submit(move || {
+        let _claim = &claim;
+        func()
+    })
+}
+
+/// Whether a callback with this key is queued or running.
+pub fn key_claimed(key: &str) -> bool {
+    CLAIMED_KEYS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|claimed| claimed.contains(key))
+}
+
+/// Submits a callback for a datum, unless one's already queued or running for the same datum, for the usual
+/// "mark this object dirty, process it once" pattern. The callback gets the datum when it runs, and is skipped
+/// if it's been qdel'd by then, same as with [`submit_while_alive`](super::submit_while_alive).
+/// Returns whether it was queued. This has to be called from the game thread. Null isn't a datum, so there's
+/// nothing to coalesce on and it's always queued.
+pub fn submit_coalesced_ref(
+    datum: &Value,
+    func: impl Fn(&Value) -> DMResult + Send + Sync + 'static,
+) -> Result<bool, SubmitError> {
+    let claim = if datum.is_truthy() {
+        // Safe to read as an id: both halves of the union are 4 bytes, and we only need something unique.
+        let key = format!("ref:{:?}:{}", datum.raw.tag, unsafe { datum.raw.data.id });
+        match KeyClaim::claim(&key) {
+            Some(claim) => Some(claim),
+            None => return Ok(false),
+        }
+    } else {
+        None
+    };
+    let held = held::Held::new(datum.clone());
+    let id = held.id();
+    submit(move || {
+        let _claim = &claim;
+        match held.take() {
+            Some(datum) if !held::is_deleted(&datum) => func(&datum),
+            _ => {
+                stats::record_stale();
+                Ok(Value::null())
+            }
+        }
+    })
+    .inspect_err(|_| {
+        held::take(id);
+    })?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_stay_claimed_until_let_go() {
+        let claim = KeyClaim::claim("test:claimed").unwrap();
+        assert!(key_claimed("test:claimed"));
+        // Like a duplicate submitted while the first is queued or running.
+        assert!(KeyClaim::claim("test:claimed").is_none());
+        assert!(!key_claimed("test:other"));
+        drop(claim);
+        assert!(!key_claimed("test:claimed"));
+        // Once it's done, the next one can take its place.
+        let replacement = KeyClaim::claim("test:claimed");
+        assert!(replacement.is_some());
+        assert!(key_claimed("test:claimed"));
+    }
+}
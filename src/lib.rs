@@ -1,15 +1,106 @@
 use auxtools::*;
 
+mod adaptive;
+mod alerts;
+#[cfg(feature = "bumpalo")]
+mod arena;
+#[cfg(feature = "async")]
+mod async_processing;
+mod clock;
+mod config;
+#[cfg(feature = "debug_server")]
+mod debug_server;
+mod errors;
+mod handle;
+mod held;
+mod keyed;
+mod lifecycle;
+mod metered;
+mod quota;
+mod stats;
+mod submit;
+#[cfg(feature = "test_support")]
+pub mod test_support;
+mod watchdog;
+pub mod wire;
+
+#[cfg(feature = "futures")]
+mod stream;
+
+#[cfg(feature = "futures")]
+pub use stream::result_stream;
+
+pub use adaptive::{
+    adaptive_budget, process_callbacks_adaptive, report_tick_pressure, set_adaptive_budget,
+};
+
+#[cfg(feature = "bumpalo")]
+pub use arena::{process_callbacks_with_arena, submit_arena, ArenaFunc};
+
+#[cfg(feature = "async")]
+pub use async_processing::{process_callbacks_async, submit_pure, PureCallback};
+
+pub use clock::{Clock, SystemClock};
+
+pub use config::CallbackConfig;
+
+#[cfg(feature = "debug_server")]
+pub use debug_server::start_debug_server;
+
+pub use errors::{
+    recent_errors, recent_errors_hook, set_batch_errors, set_error_history, set_error_ordering,
+    set_quarantine_cooldown, set_quarantine_on_error, set_report_errors, set_sweep_ids,
+    with_error_handler,
+};
+
+pub use handle::{
+    advance_generation, cancel_all_handles, cancel_before, current_generation, outstanding_handles,
+    submit_cancellable, CallbackHandle,
+};
+
+pub use keyed::{key_claimed, submit_coalesced_ref, submit_keyed, submit_unique};
+
+pub use lifecycle::{clear_lifecycle_observers, on_after_run, on_before_run, on_submit};
+
+pub use metered::{process_callbacks_metered, set_metered_rate};
+
+pub use alerts::{set_drained_proc, set_pressure_alert_proc, set_progress_proc};
+
+pub use quota::set_cpu_quota;
+
+pub use stats::{
+    avg_age, blocked_time, depth_histogram, error_count, latency_percentile, max_age, metrics_text,
+    oldest_pending_age, queue_latency_percentile, queued_bytes, set_throughput_alpha,
+    stale_callbacks, stats, take_stats, throughput, CallbackStats,
+};
+
+pub use submit::{
+    begin_shutdown, cancel_shutdown, extend_budget, extend_callbacks, in_processing,
+    mark_game_thread, set_max_budget_extension, set_max_chained_submits, set_overflow,
+    set_submit_rate_limit, set_tick_scoped, stop_processing, submit, submit_blocking,
+    submit_chunked, submit_critical, submit_if, submit_logged, submit_number, submit_or_run,
+    submit_positioned, submit_progress, submit_transaction, submit_while_alive, submit_with_cost,
+    submit_with_handler, submit_with_send_retry, NumberResult, SubmitError,
+};
+
+#[cfg(feature = "backtrace")]
+pub use submit::submit_traced;
+
+pub use watchdog::{set_watchdog, watchdog_reports, WatchdogReport};
+
+use errors::ErrorReporter;
+use submit::ProcessingScope;
+
 use std::time::{Duration, Instant};
 
 use std::sync::{
-    atomic::{AtomicBool, Ordering::Relaxed},
-    Arc,
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::Relaxed},
+    Arc, RwLock,
 };
 
 enum Timer {
     Fast(Arc<AtomicBool>),
-    Slow(Instant, Duration)
+    Slow(Instant, Duration),
 }
 
 impl Timer {
@@ -22,73 +113,870 @@ impl Timer {
             thread_done.store(true, Relaxed);
         }) {
             Ok(_) => Self::Fast(done),
-            Err(_) => Self::Slow(Instant::now(), time)
+            Err(_) => Self::Slow(Instant::now(), time),
         }
     }
     fn check(&self) -> bool {
         match self {
             Self::Fast(done) => done.load(Relaxed),
-            Self::Slow(time, duration) => time.elapsed() >= *duration
+            Self::Slow(time, duration) => time.elapsed() >= *duration,
         }
     }
 }
 
-type DeferredFunc = Box<dyn Fn() -> DMResult + Send + Sync>;
+/// A callback, as it sits in the queue.
+pub type DeferredFunc = Box<dyn Fn() -> DMResult + Send + Sync>;
 
 type CallbackChannel = (flume::Sender<DeferredFunc>, flume::Receiver<DeferredFunc>);
 
-static mut CALLBACK_CHANNEL: Option<CallbackChannel> = None;
+struct Channels {
+    main: CallbackChannel,
+    // Unbounded, and only processed once the main channel's empty. See set_overflow.
+    overflow: CallbackChannel,
+    // Unbounded, and processed after everything else. See submit_critical.
+    critical: CallbackChannel,
+}
+
+// None until the library's loaded, and again once it's shut down. Threads other than the game thread look at these
+// too, so everything takes its own copy of whichever end it needs rather than holding onto the lock.
+static CHANNELS: RwLock<Option<Channels>> = RwLock::new(None);
+
+fn with_channels<T>(f: impl FnOnce(&Channels) -> T) -> T {
+    f(CHANNELS
+        .read()
+        .unwrap()
+        .as_ref()
+        .expect("auxcallback isn't loaded"))
+}
 
 #[init(partial)]
 fn _start_callbacks() -> Result<(), String> {
-    unsafe {
-        CALLBACK_CHANNEL = Some(flume::bounded(100000));
-    }
+    *CHANNELS.write().unwrap() = Some(Channels {
+        main: flume::bounded(100000),
+        overflow: flume::unbounded(),
+        critical: flume::unbounded(),
+    });
+    #[cfg(feature = "bumpalo")]
+    arena::start();
+    #[cfg(feature = "async")]
+    async_processing::start();
+    mark_game_thread(true);
+    cancel_shutdown();
+    stats::record_processed();
     Ok(())
 }
 
 #[shutdown]
 fn _clean_callbacks() {
-    unsafe { CALLBACK_CHANNEL = None }
+    // Threads that use the queues go first, so none of them are left looking for queues that are gone.
+    wire::stop();
+    watchdog::stop();
+    #[cfg(feature = "debug_server")]
+    debug_server::stop();
+    *CHANNELS.write().unwrap() = None;
+    #[cfg(feature = "bumpalo")]
+    arena::stop();
+    #[cfg(feature = "async")]
+    async_processing::stop();
+    held::clear();
+    errors::forget_stack_trace();
 }
 
-fn with_callback_receiver<T>(f: impl Fn(&flume::Receiver<DeferredFunc>) -> T) -> T {
-    f(unsafe { &CALLBACK_CHANNEL.as_ref().unwrap().1 })
+/// Gets everything set up ahead of time, so the first processing call doesn't have to.
+/// Calling this is optional, but it's best to do it once when your library loads. It'll also tell you
+/// if `/proc/auxtools_stack_trace`, which callback errors are sent to, doesn't exist.
+pub fn init() -> DMResult<()> {
+    mark_game_thread(true);
+    match errors::find_stack_trace() {
+        Some(_) => Ok(()),
+        None => Err(runtime!(
+            "auxcallback needs /proc/auxtools_stack_trace to report callback errors to"
+        )),
+    }
+}
+
+fn with_callback_receiver<T>(f: impl FnOnce(&flume::Receiver<DeferredFunc>) -> T) -> T {
+    f(&with_channels(|channels| channels.main.1.clone()))
+}
+
+fn with_overflow_receiver<T>(f: impl FnOnce(&flume::Receiver<DeferredFunc>) -> T) -> T {
+    f(&with_channels(|channels| channels.overflow.1.clone()))
+}
+
+fn overflow_sender() -> flume::Sender<DeferredFunc> {
+    with_channels(|channels| channels.overflow.0.clone())
+}
+
+fn with_critical_receiver<T>(f: impl FnOnce(&flume::Receiver<DeferredFunc>) -> T) -> T {
+    f(&with_channels(|channels| channels.critical.1.clone()))
+}
+
+fn critical_sender() -> flume::Sender<DeferredFunc> {
+    with_channels(|channels| channels.critical.0.clone())
+}
+
+// Whether the channels are there to send to, meaning the library's been loaded and not shut down yet.
+fn channels_open() -> bool {
+    CHANNELS.read().unwrap().is_some()
 }
 
 /// This gives you a copy of the callback sender. Send to it with try_send or send, then later it'll be processed
 /// if one of the process_callbacks functions is called for any reason.
 pub fn byond_callback_sender() -> flume::Sender<DeferredFunc> {
-    unsafe { CALLBACK_CHANNEL.as_ref().unwrap().0.clone() }
+    with_channels(|channels| channels.main.0.clone())
+}
+
+// Zero if the library isn't loaded.
+fn pending_count() -> usize {
+    CHANNELS.read().unwrap().as_ref().map_or(0, |channels| {
+        channels.main.1.len() + channels.overflow.1.len() + channels.critical.1.len()
+    })
+}
+
+/// Whether callbacks are queued up but nothing's processed any of them for longer than `max_idle`, which usually
+/// means the processing call never got hooked up or stopped being called. Counts from when the library was loaded
+/// if nothing's been processed yet.
+pub fn processing_neglected(max_idle: Duration) -> bool {
+    any_pending() && stats::since_processed().is_none_or(|idle| idle > max_idle)
+}
+
+/// Whether there's anything queued at all. Cheap enough to check every tick before deciding whether to bother
+/// processing. False if the library isn't loaded.
+pub fn any_pending() -> bool {
+    channels_open()
+        && !(with_callback_receiver(|receiver| receiver.is_empty())
+            && with_overflow_receiver(|receiver| receiver.is_empty())
+            && with_critical_receiver(|receiver| receiver.is_empty()))
+}
+
+// Everything that processes callbacks goes through here first, holding onto the scope until it's done.
+// If it returns None, don't process anything.
+fn begin_processing() -> Option<ProcessingScope> {
+    note_processing();
+    if errors::quarantined() || quota::exhausted() || !gate_open() || !processing_enabled() {
+        left_over();
+        return None;
+    }
+    enter_processing(false)
+}
+
+// Same as begin_processing, but for things that have to run everything no matter what, like shutting down:
+// being quarantined, over the CPU quota, gated or turned off doesn't stop them. Being nested too deep still does.
+fn begin_forced_processing() -> Option<ProcessingScope> {
+    note_processing();
+    enter_processing(true)
+}
+
+fn note_processing() {
+    mark_game_thread(true);
+    submit::report_dropped();
+    let (len, capacity) = with_callback_receiver(|receiver| {
+        (receiver.len(), receiver.capacity().unwrap_or_default())
+    });
+    stats::record_depth(len);
+    alerts::check_pressure(len, capacity);
+}
+
+fn enter_processing(forced: bool) -> Option<ProcessingScope> {
+    let depth = submit::processing_depth();
+    if depth == 0 {
+        submit::advance_tick();
+        submit::reset_rate_limit();
+    }
+    if depth >= MAX_RECURSION.load(Relaxed) {
+        errors::report_warning(&format!(
+            "Callbacks were processed from inside callbacks {} deep, not going any further; they'll be left for later",
+            depth
+        ));
+        left_over();
+        return None;
+    }
+    stats::record_processed();
+    Some(if forced {
+        ProcessingScope::enter_forced()
+    } else {
+        ProcessingScope::enter()
+    })
+}
+
+/// Checks that the bookkeeping agrees with what's actually queued, giving back what's wrong if it doesn't.
+/// For tests, since it's only meaningful while nothing's being submitted or processed: a callback on its way into
+/// the queue, or taken out with [`take_callbacks`] and not dropped yet, is counted but not queued. For the same
+/// reason, the processing functions don't check it themselves, not even in debug builds.
+pub fn check_invariants() -> Result<(), String> {
+    if !channels_open() {
+        return Ok(());
+    }
+    if submit::processing_depth() > 0 {
+        return Err("check_invariants was called while callbacks were being processed".to_owned());
+    }
+    check_counts(
+        pending_count() + submit::deferred_count(),
+        stats::tracked_pending(),
+        stats::queued_bytes(),
+        wire::pending_messages(),
+    )
+}
+
+fn check_counts(
+    queued: usize,
+    tracked: usize,
+    queued_bytes: usize,
+    messages: usize,
+) -> Result<(), String> {
+    if tracked > queued {
+        return Err(format!(
+            "{} callbacks are tracked as pending, but only {} are queued",
+            tracked, queued
+        ));
+    }
+    if tracked == 0 && queued_bytes != 0 {
+        return Err(format!(
+            "nothing's tracked as pending, but {} bytes are counted as queued",
+            queued_bytes
+        ));
+    }
+    if messages > queued {
+        return Err(format!(
+            "{} wire messages are pending, but only {} callbacks are queued",
+            messages, queued
+        ));
+    }
+    Ok(())
+}
+
+static MAX_RECURSION: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Limits how many processing calls can be going at once when callbacks process callbacks themselves, so a callback
+/// that keeps doing that can't blow the stack. Past the limit, the nested call doesn't process anything, leaving
+/// everything queued for the outer call or the next one. No limit by default.
+pub fn set_max_recursion(max_depth: usize) {
+    MAX_RECURSION.store(max_depth, Relaxed);
+}
+
+type ProcessGate = Box<dyn Fn() -> bool + Send + Sync>;
+
+// Checked first, so there's no locking at all until a gate's been set.
+static GATED: AtomicBool = AtomicBool::new(false);
+static PROCESS_GATE: RwLock<Option<ProcessGate>> = RwLock::new(None);
+
+/// Only lets callbacks be processed while `gate` returns true, e.g. during a particular part of the round.
+/// The rest of the time, processing calls don't do anything and callbacks are left queued. Setting another
+/// one replaces it.
+pub fn set_process_gate(gate: impl Fn() -> bool + Send + Sync + 'static) {
+    *PROCESS_GATE.write().unwrap() = Some(Box::new(gate));
+    GATED.store(true, Relaxed);
+}
+
+/// Removes the gate, so callbacks can always be processed again.
+pub fn clear_process_gate() {
+    GATED.store(false, Relaxed);
+    *PROCESS_GATE.write().unwrap() = None;
+}
+
+static PROCESSING_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turns processing on or off altogether, e.g. from an admin panel. While it's off, processing calls don't do
+/// anything, but callbacks can still be submitted and are kept queued until it's turned back on. On by default.
+pub fn set_processing_enabled(enabled: bool) {
+    PROCESSING_ENABLED.store(enabled, Relaxed);
+}
+
+pub fn processing_enabled() -> bool {
+    PROCESSING_ENABLED.load(Relaxed)
+}
+
+/// Calls [`set_processing_enabled`] from DM, with whether the first argument is truthy.
+/// Like the other hooks, this has to be manually hooked, e.g.
+/// ```
+/// # use auxtools::*;
+/// #[hook("/proc/set_callback_enabled")]
+/// fn _set_callback_enabled() {
+///     auxcallback::set_callback_enabled_hook(args)
+/// }
+/// ```
+pub fn set_callback_enabled_hook(args: &mut [Value]) -> DMResult {
+    set_processing_enabled(args.first().is_some_and(|enabled| enabled.is_truthy()));
+    Ok(Value::null())
+}
+
+/// Gives DM [`processing_enabled`]. Like the other hooks, this has to be manually hooked, e.g.
+/// ```
+/// # use auxtools::*;
+/// #[hook("/proc/get_callback_enabled")]
+/// fn _get_callback_enabled() {
+///     auxcallback::get_callback_enabled_hook()
+/// }
+/// ```
+pub fn get_callback_enabled_hook() -> DMResult {
+    Ok(Value::from(processing_enabled()))
+}
+
+fn gate_open() -> bool {
+    !GATED.load(Relaxed)
+        || PROCESS_GATE
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_none_or(|gate| gate())
+}
+
+// Every callback that's been run, for telling whether a processing call got anywhere.
+static CALLBACKS_RUN: AtomicU64 = AtomicU64::new(0);
+
+// Takes the callback by value so calling it drops it straight away, letting go of anything it captured (like held
+// values) before its error is reported or the next one is pulled off the queue. Everything that runs callbacks
+// should go through here, or at least drop them the same way.
+
+fn run_callback(callback: impl FnOnce() -> DMResult, errors: &mut ErrorReporter) {
+    CALLBACKS_RUN.fetch_add(1, Relaxed);
+    errors.callback_started();
+    lifecycle::before_run();
+    let outer = watchdog::callback_started();
+    let started = Instant::now();
+    let result = callback();
+    let elapsed = started.elapsed();
+    stats::record_latency(elapsed);
+    quota::record(elapsed);
+    watchdog::callback_finished(outer);
+    lifecycle::after_run(&result);
+    #[cfg(feature = "futures")]
+    stream::publish_result(&result);
+    if let Err(e) = result {
+        errors.report(e.message);
+    }
+}
+
+// In nanoseconds; u64::MAX means there isn't one.
+static DEFAULT_BUDGET: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Gives [`process_callbacks`] a time limit, so it works like [`process_callbacks_for`] with that duration.
+/// None, the default, means it runs every callback no matter how long it takes.
+pub fn set_default_budget(budget: Option<Duration>) {
+    let nanos = budget.map_or(u64::MAX, |budget| {
+        budget.as_nanos().min(u64::MAX as u128 - 1) as u64
+    });
+    DEFAULT_BUDGET.store(nanos, Relaxed);
+}
+
+fn default_budget() -> Option<Duration> {
+    match DEFAULT_BUDGET.load(Relaxed) {
+        u64::MAX => None,
+        nanos => Some(Duration::from_nanos(nanos)),
+    }
+}
+
+// In nanoseconds.
+static BOOST: AtomicU64 = AtomicU64::new(0);
+
+/// Gives the next call to [`process_callbacks_for`], or anything else that goes through it like a budgeted
+/// [`process_callbacks`] or the processing hook, `extra` more time than it was asked for. For bursts of callbacks
+/// you know about ahead of time, without changing the budget for good. Boosts add up until they're used.
+pub fn boost_next_tick(extra: Duration) {
+    let nanos = extra.as_nanos().min(u64::MAX as u128) as u64;
+    let _ = BOOST.fetch_update(Relaxed, Relaxed, |boost| Some(boost.saturating_add(nanos)));
+}
+
+fn take_boost() -> Duration {
+    Duration::from_nanos(BOOST.swap(0, Relaxed))
+}
+
+fn stack_trace_proc() -> Proc {
+    errors::handler_override().unwrap_or_else(|| errors::find_stack_trace().unwrap())
 }
 
 /// Goes through every single outstanding callback and calls them.
+/// If a default budget has been set with [`set_default_budget`], it stops once that's used up instead.
 pub fn process_callbacks() {
-    let stack_trace = Proc::find("/proc/auxtools_stack_trace").unwrap();
-    with_callback_receiver(|receiver| {
-        for callback in receiver.try_iter() {
-            if let Err(e) = callback() {
-                let _ = stack_trace.call(&[&Value::from_string(e.message.as_str()).unwrap()]);
-            }
+    process_callbacks_with_handler(&stack_trace_proc())
+}
+
+/// Same as [`process_callbacks`], but errors are sent to the given proc instead of `/proc/auxtools_stack_trace`.
+/// Saves looking the proc up every time, if you're calling this a lot.
+pub fn process_callbacks_with_handler(handler: &Proc) {
+    if let Some(budget) = default_budget() {
+        process_callbacks_for_with_handler(budget, handler);
+        return;
+    }
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return,
+    };
+    process_all_until(&|| false, &mut ErrorReporter::new(handler.clone()));
+}
+
+/// Calls [`process_callbacks`] when dropped. See [`scoped_processing`].
+#[must_use = "callbacks are processed when the guard is dropped"]
+pub struct ProcessGuard {
+    _private: (),
+}
+
+impl Drop for ProcessGuard {
+    fn drop(&mut self) {
+        process_callbacks();
+    }
+}
+
+/// Makes sure any callbacks sent during a scope get run by the end of it, e.g.
+/// ```no_run
+/// # fn set_up_things_that_defer_stuff() {}
+/// {
+///     let _guard = auxcallback::scoped_processing();
+///     set_up_things_that_defer_stuff();
+/// } // everything deferred gets run here
+/// ```
+/// Errors go to the stack trace proc as usual.
+pub fn scoped_processing() -> ProcessGuard {
+    ProcessGuard { _private: () }
+}
+
+/// Goes through every single outstanding callback and calls them, handing back all their results in order
+/// instead of sending errors to the stack trace proc. Meant for testing, not for the usual processing.
+pub fn run_and_collect() -> Vec<DMResult> {
+    // Quarantine is about not spamming errors, which this doesn't report anyway.
+    let _processing = begin_processing().unwrap_or_else(ProcessingScope::enter);
+    let mut results: Vec<DMResult> =
+        with_callback_receiver(|receiver| receiver.try_iter().map(|callback| callback()).collect());
+    with_overflow_receiver(|receiver| {
+        results.extend(receiver.try_iter().map(|callback| callback()))
+    });
+    with_critical_receiver(|receiver| {
+        results.extend(receiver.try_iter().map(|callback| callback()))
+    });
+    results
+}
+
+/// Goes through every single outstanding callback and calls them, like [`process_callbacks`] without a budget.
+/// Errors are reported as usual, and also handed back along with the position of the callback that caused them,
+/// counting from zero in the order they were run.
+pub fn process_callbacks_detailed() -> Vec<(usize, String)> {
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return Vec::new(),
+    };
+    let mut errors = ErrorReporter::new(stack_trace_proc()).recording();
+    process_all_until(&|| false, &mut errors);
+    errors.take_recorded()
+}
+
+// Whether it stopped before getting to the end of the queue, which it might've reached anyway if the stop came
+// with the last callback. Check whether anything's left before saying there was.
+fn process_receiver_until(
+    receiver: &flume::Receiver<DeferredFunc>,
+    should_stop: &dyn Fn() -> bool,
+    errors: &mut ErrorReporter,
+) -> bool {
+    for callback in receiver.try_iter() {
+        run_callback(callback, errors);
+        if submit::take_stop_request()
+            || (!submit::processing_forced() && quota::exhausted())
+            || should_stop()
+        {
+            return true;
         }
-    })
+    }
+    false
+}
+
+fn process_all_until(should_stop: &dyn Fn() -> bool, errors: &mut ErrorReporter) -> bool {
+    let had_pending = any_pending();
+    let stopped =
+        with_callback_receiver(|receiver| process_receiver_until(receiver, should_stop, errors))
+            || with_overflow_receiver(|receiver| {
+                process_receiver_until(receiver, should_stop, errors)
+            })
+            || with_critical_receiver(|receiver| {
+                process_receiver_until(receiver, should_stop, errors)
+            });
+    let stopped = stopped && any_pending();
+    FULLY_DRAINED.store(!stopped, Relaxed);
+    if had_pending && !stopped {
+        alerts::drained();
+    }
+    stopped
+}
+
+static FULLY_DRAINED: AtomicBool = AtomicBool::new(true);
+
+/// Whether the last processing call got through everything that was queued, rather than stopping early with
+/// callbacks left over. Calls that didn't run anything, like when processing's turned off, count as stopping early if
+/// anything was queued. Callbacks submitted since then don't change it.
+pub fn fully_drained() -> bool {
+    FULLY_DRAINED.load(Relaxed)
+}
+
+// For processing calls that return without going through process_all_until: whether anything's still queued,
+// keeping fully_drained up to date with it.
+fn left_over() -> bool {
+    let left_over = pending_count() > 0;
+    FULLY_DRAINED.store(!left_over, Relaxed);
+    left_over
+}
+
+fn note_overrun() {
+    let remaining = pending_count();
+    if remaining > 0 {
+        stats::record_overrun(remaining);
+    }
 }
 
 /// Goes through every single outstanding callback and calls them, until a given time limit is reached.
 pub fn process_callbacks_for(duration: Duration) -> bool {
-    let stack_trace = Proc::find("/proc/auxtools_stack_trace").unwrap();
+    process_callbacks_for_with_handler(duration, &stack_trace_proc())
+}
+
+/// Same as [`process_callbacks_for`], but errors are sent to the given proc instead of `/proc/auxtools_stack_trace`.
+pub fn process_callbacks_for_with_handler(duration: Duration, handler: &Proc) -> bool {
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return pending_count() > 0,
+    };
+    let duration = duration.saturating_add(take_boost());
     let timer = Timer::new(duration);
-    with_callback_receiver(|receiver| {
-        for callback in receiver.try_iter() {
-            if let Err(e) = callback() {
-                let _ = stack_trace.call(&[&Value::from_string(e.message.as_str()).unwrap()]);
-            }
-            if timer.check() {
-                return true;
-            }
+    let start = Instant::now();
+    let extension = std::cell::Cell::new(Duration::ZERO);
+    let out_of_time = || {
+        extension.set(submit::take_extension(extension.get()));
+        timer.check() && start.elapsed() >= duration.saturating_add(extension.get())
+    };
+    let timed_out = process_all_until(&out_of_time, &mut ErrorReporter::new(handler.clone()));
+    // A callback could have stopped it early with stop_processing, which isn't an overrun.
+    if timed_out && out_of_time() {
+        note_overrun();
+    }
+    timed_out
+}
+
+/// Same as [`process_callbacks_for`], but the time comes from `clock`, checked after every callback.
+/// Mostly useful for tests, where a clock you move forward yourself makes the cutoff predictable.
+pub fn process_callbacks_for_with_clock(duration: Duration, clock: &impl Clock) -> bool {
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return pending_count() > 0,
+    };
+    let start = clock.now();
+    let out_of_time = || clock.now().saturating_duration_since(start) >= duration;
+    let timed_out = process_all_until(&out_of_time, &mut ErrorReporter::new(stack_trace_proc()));
+    if timed_out && out_of_time() {
+        note_overrun();
+    }
+    timed_out
+}
+
+/// Same as [`process_callbacks_for`], but stops at `deadline` rather than after a length of time, for when you've
+/// already got the end of the tick as an `Instant`. Still runs at least one callback if the deadline's passed.
+pub fn process_callbacks_until_instant(deadline: Instant) -> bool {
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return pending_count() > 0,
+    };
+    let out_of_time = || Instant::now() >= deadline;
+    let timed_out = process_all_until(&out_of_time, &mut ErrorReporter::new(stack_trace_proc()));
+    if timed_out && out_of_time() {
+        note_overrun();
+    }
+    timed_out
+}
+
+/// Same as [`process_callbacks_for`] with `soft` as the time limit, but warns if a callback that started before
+/// then kept going past `hard`. Callbacks can't be interrupted, so this can't stop one from blowing past `hard`,
+/// only tell you it did so you can find out which callback it was.
+pub fn process_callbacks_soft_hard(soft: Duration, hard: Duration) -> bool {
+    let started = Instant::now();
+    let timed_out = process_callbacks_for(soft);
+    let took = started.elapsed();
+    if took > hard {
+        errors::report_warning(&format!(
+            "Processing callbacks took {:?}, past its hard limit of {:?}",
+            took, hard
+        ));
+    }
+    timed_out
+}
+
+/// Runs up to `max` callbacks, calling `yield_fn` after each one and stopping if it returns false. That way
+/// whatever's calling this can check something like `world.tick_usage` between every callback, rather than
+/// going by a time limit. Returns whether it stopped with callbacks left over.
+pub fn process_callbacks_cooperative(max: usize, yield_fn: impl FnMut() -> bool) -> bool {
+    if max == 0 {
+        return left_over();
+    }
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return pending_count() > 0,
+    };
+    let ran = std::cell::Cell::new(0);
+    let yield_fn = std::cell::RefCell::new(yield_fn);
+    let should_stop = || {
+        ran.set(ran.get() + 1);
+        ran.get() >= max || !(yield_fn.borrow_mut())()
+    };
+    process_all_until(&should_stop, &mut ErrorReporter::new(stack_trace_proc()))
+}
+
+/// Runs callbacks until their costs add up to `budget`, however long that takes, for batches that are the same
+/// size no matter how fast the machine is. Callbacks cost 1 unless they're sent with [`submit_with_cost`].
+/// Returns whether it stopped with callbacks left over.
+pub fn process_callbacks_cost_budget(budget: u64) -> bool {
+    if budget == 0 {
+        return left_over();
+    }
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return pending_count() > 0,
+    };
+    // Whatever ran last outside of this could have left its cost behind.
+    submit::take_cost();
+    let spent = std::cell::Cell::new(0u64);
+    let over_budget = || {
+        spent.set(spent.get().saturating_add(submit::take_cost()));
+        spent.get() >= budget
+    };
+    process_all_until(&over_budget, &mut ErrorReporter::new(stack_trace_proc()))
+}
+
+/// Same as [`process_callbacks_for`], but runs callbacks in batches of up to `batch_size`, calling `before` at
+/// the start of each batch and `after` at the end, so setup that's too expensive to do for every callback can be
+/// shared between them. Returns whether there were callbacks left over.
+pub fn process_callbacks_batched(
+    batch_size: usize,
+    before: impl Fn(),
+    after: impl Fn(),
+    duration: Duration,
+) -> bool {
+    if batch_size == 0 {
+        return left_over();
+    }
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return pending_count() > 0,
+    };
+    let timer = Timer::new(duration);
+    let mut errors = ErrorReporter::new(stack_trace_proc());
+    while pending_count() > 0 {
+        let ran = std::cell::Cell::new(0);
+        let batch_done = || {
+            ran.set(ran.get() + 1);
+            ran.get() >= batch_size || timer.check()
+        };
+        before();
+        let stopped = process_all_until(&batch_done, &mut errors);
+        after();
+        if timer.check() {
+            note_overrun();
+            return left_over();
         }
-        false
-    })
+        // Stopped partway through a batch, so something other than the batch size or the time stopped it.
+        if stopped && ran.get() < batch_size {
+            return left_over();
+        }
+    }
+    false
+}
+
+/// Takes callbacks from `produce` one at a time, submitting each and then running whatever's queued, until
+/// `produce` runs out or `budget` is used up. For streaming work through without building the whole batch
+/// up front. Returns whether it stopped with work left over, or the error if a callback couldn't be submitted,
+/// in which case that callback's lost.
+pub fn pump(
+    mut produce: impl FnMut() -> Option<DeferredFunc>,
+    budget: Duration,
+) -> Result<bool, SubmitError> {
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return Ok(pending_count() > 0),
+    };
+    let timer = Timer::new(budget);
+    let mut errors = ErrorReporter::new(stack_trace_proc());
+    loop {
+        let func = match produce() {
+            Some(func) => func,
+            None => return Ok(left_over()),
+        };
+        submit::submit_boxed(func)?;
+        if process_all_until(&|| timer.check(), &mut errors) || timer.check() {
+            return Ok(true);
+        }
+    }
+}
+
+// How many callbacks process_callbacks_with_sleep still has to get through; 0 means it isn't spreading one out.
+static SPREAD_REMAINING: AtomicUsize = AtomicUsize::new(0);
+
+/// Spreads everything that's queued right now over as many ticks as it takes, `chunk` callbacks a tick.
+/// Each call runs the next chunk, and if there's more to go calls `sleep_proc`, which should sleep until next
+/// tick and then call this again, e.g. through a hook:
+/// ```dm
+/// /proc/callback_sleep()
+///     set waitfor = FALSE
+///     sleep(world.tick_lag)
+///     process_callbacks_with_sleep()
+/// ```
+/// Where it's up to is kept between calls, and callbacks submitted in the meantime wait for the next round
+/// rather than keeping it going forever. Returns whether there's more to go. A `chunk` of zero would never get
+/// anywhere, so it runs nothing and doesn't call `sleep_proc`.
+pub fn process_callbacks_with_sleep(chunk: usize, sleep_proc: &Proc) -> bool {
+    if chunk == 0 {
+        return false;
+    }
+    let processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return SPREAD_REMAINING.load(Relaxed) > 0,
+    };
+    let mut remaining = SPREAD_REMAINING.load(Relaxed);
+    if remaining == 0 {
+        remaining = pending_count();
+    }
+    let max = chunk.min(remaining);
+    let ran = std::cell::Cell::new(0);
+    if max > 0 {
+        let done_with_chunk = || {
+            ran.set(ran.get() + 1);
+            ran.get() >= max
+        };
+        process_all_until(
+            &done_with_chunk,
+            &mut ErrorReporter::new(stack_trace_proc()),
+        );
+    }
+    drop(processing);
+    let remaining = if pending_count() > 0 {
+        remaining - ran.get()
+    } else {
+        0
+    };
+    SPREAD_REMAINING.store(remaining, Relaxed);
+    if remaining == 0 {
+        return false;
+    }
+    if let Err(e) = sleep_proc.call(&[]) {
+        errors::report_warning(&format!(
+            "Couldn't sleep between chunks of callbacks: {}",
+            e.message
+        ));
+    }
+    true
+}
+
+/// Runs only the callbacks that spilled into the overflow queue (see [`set_overflow`]), for up to `max`.
+/// Meant for spare time between ticks, leaving the main queue for the usual processing call each tick.
+/// Returns whether there were overflow callbacks left over.
+pub fn process_background(max: Duration) -> bool {
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return with_overflow_receiver(|receiver| !receiver.is_empty()),
+    };
+    let timer = Timer::new(max);
+    let mut errors = ErrorReporter::new(stack_trace_proc());
+    let timed_out = with_overflow_receiver(|receiver| {
+        process_receiver_until(receiver, &|| timer.check(), &mut errors) && !receiver.is_empty()
+    });
+    left_over();
+    timed_out
+}
+
+/// Like [`process_callbacks_for`], but stops running ordinary callbacks `reserve` early,
+/// keeping the rest of the time for callbacks sent with [`submit_critical`], so cleanup still gets to run
+/// when there's a big backlog. Returns whether anything was left over.
+pub fn process_callbacks_reserved(total: Duration, reserve: Duration) -> bool {
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return pending_count() > 0,
+    };
+    let mut errors = ErrorReporter::new(stack_trace_proc());
+    let ordinary_timer = Timer::new(total.saturating_sub(reserve));
+    let total_timer = Timer::new(total);
+    let ordinary_stop = || ordinary_timer.check();
+    let timed_out = with_callback_receiver(|receiver| {
+        process_receiver_until(receiver, &ordinary_stop, &mut errors)
+    }) || with_overflow_receiver(|receiver| {
+        process_receiver_until(receiver, &ordinary_stop, &mut errors)
+    });
+    let timed_out = with_critical_receiver(|receiver| {
+        process_receiver_until(receiver, &|| total_timer.check(), &mut errors)
+    }) || timed_out;
+    let timed_out = timed_out && any_pending();
+    FULLY_DRAINED.store(!timed_out, Relaxed);
+    if timed_out && ordinary_timer.check() {
+        note_overrun();
+    }
+    timed_out
+}
+
+/// How [`wait_until_empty`] waits between checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitStrategy {
+    /// Checks again straight away. Notices soonest, at the cost of a whole core.
+    Spin,
+    /// Lets the OS run something else between checks.
+    Yield,
+    /// Sleeps this long between checks. Easiest on the CPU.
+    Park(Duration),
+}
+
+/// Blocks until everything queued has been processed, or `timeout` runs out. Returns whether the queue emptied.
+/// This is for other threads to wait on the game thread: called from the game thread, nothing gets processed
+/// while it's waiting, so it'll just time out. It's false straight away if the library isn't loaded, or once it
+/// shuts down, since then nothing's going to process what was queued.
+pub fn wait_until_empty(strategy: WaitStrategy, timeout: Duration) -> bool {
+    let start = Instant::now();
+    loop {
+        if !channels_open() {
+            return false;
+        }
+        if pending_count() == 0 {
+            return true;
+        }
+        if start.elapsed() >= timeout {
+            return false;
+        }
+        match strategy {
+            WaitStrategy::Spin => std::hint::spin_loop(),
+            WaitStrategy::Yield => std::thread::yield_now(),
+            WaitStrategy::Park(interval) => std::thread::sleep(interval),
+        }
+    }
+}
+
+/// Lets another thread stop [`process_callbacks_cancellable`] partway through. This stops the processing,
+/// not the callbacks; whatever's left stays queued.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops any processing using this token after the callback it's currently running.
+    pub fn cancel(&self) {
+        self.0.store(true, Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Relaxed)
+    }
+}
+
+/// Same as [`process_callbacks_for`], but also stops as soon as the token is cancelled.
+/// Returns whether it stopped before running out of callbacks, for either reason.
+pub fn process_callbacks_cancellable(token: &CancellationToken, duration: Duration) -> bool {
+    if token.is_cancelled() {
+        return left_over();
+    }
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return pending_count() > 0,
+    };
+    let timer = Timer::new(duration);
+    let stopped = process_all_until(
+        &|| token.is_cancelled() || timer.check(),
+        &mut ErrorReporter::new(stack_trace_proc()),
+    );
+    if stopped && timer.check() {
+        note_overrun();
+    }
+    stopped
 }
 
 /// Goes through every single outstanding callback and calls them, until a given time limit in milliseconds is reached.
@@ -96,27 +984,423 @@ pub fn process_callbacks_for_millis(millis: u64) -> bool {
     process_callbacks_for(Duration::from_millis(millis))
 }
 
+// If the queue keeps getting refilled as fast as it's drained, process_callbacks_until gives up after this many goes.
+const MAX_UNTIL_ITERATIONS: usize = 1000;
+
+/// Keeps calling [`process_callbacks_for`] with `per_call` until `predicate` is true of the number of callbacks
+/// still queued, or the queue's empty. Gives up after a thousand goes, in case callbacks are coming in as fast
+/// as they're going out, or as soon as a go doesn't run anything, like when processing's turned off.
+/// Returns whether there were callbacks left over.
+pub fn process_callbacks_until(predicate: impl Fn(usize) -> bool, per_call: Duration) -> bool {
+    for _ in 0..MAX_UNTIL_ITERATIONS {
+        let remaining = pending_count();
+        if remaining == 0 || predicate(remaining) {
+            return left_over();
+        }
+        let ran = CALLBACKS_RUN.load(Relaxed);
+        process_callbacks_for(per_call);
+        if CALLBACKS_RUN.load(Relaxed) == ran {
+            break;
+        }
+    }
+    left_over()
+}
+
 /// This function is to be called from byond, preferably once a tick.
 /// Calling with no arguments will process every outstanding callback.
 /// Calling with one argument will process the callbacks until a given time limit is reached.
-/// Time limit is in milliseconds.
+/// Time limit is in milliseconds, and can be fractional. A time limit of zero processes nothing, which
+/// is what you get from passing in the remaining tick time when the tick is already overrun.
+/// Negative time limits are a runtime, and one too big to keep track of, like infinity, runs everything
+/// the way [`drain_callbacks`] does.
+/// Returns whether there were callbacks left over when the time ran out.
 /// This has to be manually hooked in the code, e.g.
 /// ```
+/// # use auxtools::*;
 /// #[hook("/proc/process_atmos_callbacks")]
 /// fn _atmos_callback_handle() {
 ///     auxcallback::callback_processing_hook(args)
 /// }
 /// ```
-
-pub fn callback_processing_hook(args: &mut Vec<Value>) -> DMResult {
-    match args.len() {
-        0 => {
+pub fn callback_processing_hook(args: &mut [Value]) -> DMResult {
+    let arg_limit = match args.first() {
+        None => {
             process_callbacks();
-            Ok(Value::null())
+            return Ok(Value::null());
         }
-        _ => {
-            let arg_limit = args.get(0).unwrap().as_number()? as u64;
-            Ok(Value::from(process_callbacks_for_millis(arg_limit)))
+        Some(arg) => arg.as_number()?,
+    };
+    match HookLimit::from_millis(arg_limit) {
+        HookLimit::Invalid => Err(runtime!(
+            "Callback time limit must be a non-negative number of milliseconds, got {}",
+            arg_limit
+        )),
+        HookLimit::Nothing => Ok(Value::from(left_over())),
+        HookLimit::For(limit) => Ok(Value::from(process_callbacks_for(limit))),
+        HookLimit::Unlimited => Ok(Value::from(drain_callbacks())),
+    }
+}
+
+// What a time limit given to callback_processing_hook asks for.
+#[derive(Debug, PartialEq)]
+enum HookLimit {
+    // NaN or negative.
+    Invalid,
+    Nothing,
+    For(Duration),
+    // Too big to be a Duration, infinity included, so there's no limit worth speaking of.
+    Unlimited,
+}
+
+impl HookLimit {
+    fn from_millis(millis: f32) -> Self {
+        if millis.is_nan() || millis < 0.0 {
+            Self::Invalid
+        } else if millis == 0.0 {
+            Self::Nothing
+        } else {
+            match Duration::try_from_secs_f32(millis / 1000.0) {
+                Ok(limit) => Self::For(limit),
+                Err(_) => Self::Unlimited,
+            }
         }
     }
 }
+
+/// Goes through every single outstanding callback and calls them, even if [`set_default_budget`] has given
+/// [`process_callbacks`] a time limit. Being quarantined, over the CPU quota, gated or turned off doesn't stop
+/// it either. Returns whether there were callbacks left over anyway, which can only happen if processing's nested
+/// too deep, or one of them called [`stop_processing`].
+pub fn drain_callbacks() -> bool {
+    let _processing = match begin_forced_processing() {
+        Some(processing) => processing,
+        None => return pending_count() > 0,
+    };
+    process_all_until(&|| false, &mut ErrorReporter::new(stack_trace_proc()))
+}
+
+/// Calls [`drain_callbacks`] from DM, for when everything has to be done now no matter how long it takes,
+/// like before a reboot. Returns whether there were callbacks left over.
+/// Like the processing hook, this has to be manually hooked, e.g.
+/// ```
+/// # use auxtools::*;
+/// #[hook("/proc/drain_callbacks")]
+/// fn _drain_callbacks() {
+///     auxcallback::drain_callbacks_hook()
+/// }
+/// ```
+pub fn drain_callbacks_hook() -> DMResult {
+    Ok(Value::from(drain_callbacks()))
+}
+
+/// How a [`shutdown`] went.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// How many ordinary callbacks were run, including ones that spilled into the overflow queue.
+    pub ordinary_run: usize,
+    /// How many callbacks sent with [`submit_critical`] were run.
+    pub critical_run: usize,
+    /// How many callbacks were still queued when it ran out of time.
+    pub remaining: usize,
+    /// Whether everything was run before the deadline.
+    pub completed: bool,
+}
+
+/// Shuts down in order, for round end: stops taking new callbacks with [`begin_shutdown`], runs the ordinary
+/// callbacks, then the critical ones, all within `total`. Ordinary callbacks only get three quarters of it at
+/// most, so cleanup sent with [`submit_critical`] still gets to run when there's a big backlog. Like
+/// [`drain_callbacks`], it runs even if processing's quarantined, over the CPU quota, gated or turned off.
+pub fn shutdown(total: Duration) -> ShutdownReport {
+    begin_shutdown();
+    let ordinary_pending = || {
+        with_callback_receiver(|receiver| receiver.len())
+            + with_overflow_receiver(|receiver| receiver.len())
+    };
+    let critical_pending = || with_critical_receiver(|receiver| receiver.len());
+    let ordinary_before = ordinary_pending();
+    let critical_before = critical_pending();
+    if let Some(_processing) = begin_forced_processing() {
+        let mut errors = ErrorReporter::new(stack_trace_proc());
+        let ordinary_timer = Timer::new(total - total / 4);
+        let total_timer = Timer::new(total);
+        let _ = with_callback_receiver(|receiver| {
+            process_receiver_until(receiver, &|| ordinary_timer.check(), &mut errors)
+        }) || with_overflow_receiver(|receiver| {
+            process_receiver_until(receiver, &|| ordinary_timer.check(), &mut errors)
+        });
+        with_critical_receiver(|receiver| {
+            process_receiver_until(receiver, &|| total_timer.check(), &mut errors)
+        });
+    }
+    let remaining = ordinary_pending() + critical_pending();
+    FULLY_DRAINED.store(remaining == 0, Relaxed);
+    ShutdownReport {
+        ordinary_run: ordinary_before.saturating_sub(ordinary_pending()),
+        critical_run: critical_before.saturating_sub(critical_pending()),
+        remaining,
+        completed: remaining == 0,
+    }
+}
+
+/// Takes up to `max` callbacks out of the queue without running them, in the order they'd have been processed,
+/// so you can run them however you like. They skip everything the processing functions do, like error
+/// reporting and stats, unless you do it yourself. Empty if the library isn't loaded.
+pub fn take_callbacks(max: usize) -> Vec<DeferredFunc> {
+    if !channels_open() {
+        return Vec::new();
+    }
+    let mut taken: Vec<DeferredFunc> =
+        with_callback_receiver(|receiver| receiver.try_iter().take(max).collect());
+    taken.extend(with_overflow_receiver(|receiver| {
+        receiver
+            .try_iter()
+            .take(max - taken.len())
+            .collect::<Vec<_>>()
+    }));
+    taken.extend(with_critical_receiver(|receiver| {
+        receiver
+            .try_iter()
+            .take(max - taken.len())
+            .collect::<Vec<_>>()
+    }));
+    taken
+}
+
+/// Runs the next callback and nothing else, for stepping through the queue while debugging. Returns whether
+/// there was one to run, or the callback's runtime if it had one, which is also reported like usual.
+/// Callbacks that were cancelled still count as a step, they just don't do anything.
+pub fn step() -> DMResult<bool> {
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return Ok(false),
+    };
+    let callback = with_callback_receiver(|receiver| receiver.try_recv().ok())
+        .or_else(|| with_overflow_receiver(|receiver| receiver.try_recv().ok()))
+        .or_else(|| with_critical_receiver(|receiver| receiver.try_recv().ok()));
+    let callback = match callback {
+        Some(callback) => callback,
+        None => {
+            left_over();
+            return Ok(false);
+        }
+    };
+    let mut failure = None;
+    let failure_ref = &mut failure;
+    run_callback(
+        move || {
+            let result = callback();
+            if let Err(e) = &result {
+                *failure_ref = Some(e.message.clone());
+            }
+            result
+        },
+        &mut ErrorReporter::new(stack_trace_proc()),
+    );
+    left_over();
+    match failure {
+        Some(message) => Err(runtime!(message)),
+        None => Ok(true),
+    }
+}
+
+/// Calls [`step`] from DM, so an admin can run a stuck queue one callback at a time.
+/// Like the other hooks, this has to be manually hooked, e.g.
+/// ```
+/// # use auxtools::*;
+/// #[hook("/proc/step_callback")]
+/// fn _step_callback() {
+///     auxcallback::step_callback_hook()
+/// }
+/// ```
+pub fn step_callback_hook() -> DMResult {
+    Ok(Value::from(step()?))
+}
+
+/// Defers a DM `/datum/callback`, calling its `Invoke()` when the callbacks are next processed.
+/// The callback is skipped if it gets qdel'd before then.
+/// Like the processing hook, this has to be manually hooked, e.g.
+/// ```
+/// # use auxtools::*;
+/// #[hook("/proc/defer_callback")]
+/// fn _defer_callback() {
+///     auxcallback::defer_callback_hook(args)
+/// }
+/// ```
+/// and then on the DM side, with a stub for the hook to replace:
+/// ```dm
+/// /proc/defer_callback(datum/callback/cb)
+///
+/// /datum/foo/proc/bar()
+///     defer_callback(CALLBACK(src, .proc/baz))
+/// ```
+/// This goes through the same checks as [`submit`], so it runtimes if the callback can't be queued (the queue is
+/// full with nowhere to overflow to, it's rate limited, or it's shutting down) rather than waiting.
+pub fn defer_callback_hook(args: &mut [Value]) -> DMResult {
+    let callback = match args.first() {
+        Some(callback) if callback.is_truthy() => callback.clone(),
+        _ => return Err(runtime!("defer_callback needs a callback datum to defer")),
+    };
+    let held = held::Held::new(callback);
+    let func: DeferredFunc = Box::new(move || match held.take() {
+        Some(callback) if !held::callback_is_dead(&callback) => callback.call("Invoke", &[]),
+        _ => Ok(Value::null()),
+    });
+    // The callback comes back in the error and lets go of the datum when it's dropped.
+    submit::submit_boxed(func).map_err(|err| runtime!("Could not defer callback: {}", err))?;
+    Ok(Value::null())
+}
+
+/// The crate version and the features it was built with, like `auxcallback 0.2.1 (features: async, futures)`.
+pub fn version_info() -> &'static str {
+    static VERSION_INFO: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    VERSION_INFO.get_or_init(|| {
+        let features: Vec<&str> = [
+            ("async", cfg!(feature = "async")),
+            ("backtrace", cfg!(feature = "backtrace")),
+            ("bumpalo", cfg!(feature = "bumpalo")),
+            ("debug_server", cfg!(feature = "debug_server")),
+            ("futures", cfg!(feature = "futures")),
+            ("metrics", cfg!(feature = "metrics")),
+            ("pending_age", cfg!(feature = "pending_age")),
+            ("test_support", cfg!(feature = "test_support")),
+            ("tracing", cfg!(feature = "tracing")),
+        ]
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| *name)
+        .collect();
+        if features.is_empty() {
+            format!("auxcallback {}", env!("CARGO_PKG_VERSION"))
+        } else {
+            format!(
+                "auxcallback {} (features: {})",
+                env!("CARGO_PKG_VERSION"),
+                features.join(", ")
+            )
+        }
+    })
+}
+
+/// Gives DM the string from [`version_info`], so you can check which build a server's running.
+/// Like the other hooks, this has to be manually hooked, e.g.
+/// ```
+/// # use auxtools::*;
+/// #[hook("/proc/auxcallback_version")]
+/// fn _auxcallback_version() {
+///     auxcallback::version_hook()
+/// }
+/// ```
+pub fn version_hook() -> DMResult {
+    Value::from_string(version_info())
+}
+
+/// Everything there is to know about the state of callback processing, written out for people to read, e.g. to
+/// paste into a bug report. The format isn't stable; use [`stats`] or [`metrics_text`] for anything that reads it.
+pub fn debug_dump() -> String {
+    use std::fmt::Write;
+    let mut dump = String::new();
+    let _ = writeln!(dump, "{}", version_info());
+    if channels_open() {
+        let _ = writeln!(
+            dump,
+            "queued: {} main (capacity {}), {} overflow, {} critical",
+            with_callback_receiver(|receiver| receiver.len()),
+            with_callback_receiver(|receiver| receiver.capacity().unwrap_or_default()),
+            with_overflow_receiver(|receiver| receiver.len()),
+            with_critical_receiver(|receiver| receiver.len())
+        );
+    } else {
+        let _ = writeln!(dump, "queued: not loaded");
+    }
+    let _ = writeln!(
+        dump,
+        "enabled: {}, shutting down: {}, quarantined: {}, last call fully drained: {}",
+        processing_enabled(),
+        submit::shutting_down(),
+        errors::quarantined(),
+        fully_drained()
+    );
+    if let Some(idle) = stats::since_processed() {
+        let _ = writeln!(dump, "last processed: {:?} ago", idle);
+    }
+    let _ = writeln!(dump, "outstanding handles: {}", outstanding_handles());
+    let _ = writeln!(dump, "stats: {:?}", stats());
+    for p in [0.5, 0.95, 0.99] {
+        if let Some(latency) = stats::latency_percentile(p) {
+            let _ = writeln!(dump, "p{} latency: under {:?}", p * 100.0, latency);
+        }
+    }
+    let _ = writeln!(dump, "throughput: {:.1}/s", stats::throughput());
+    let recent = recent_errors();
+    if recent.is_empty() {
+        let _ = writeln!(dump, "recent errors: none kept");
+    } else {
+        let _ = writeln!(dump, "recent errors:");
+        for message in recent {
+            let _ = writeln!(dump, "  {}", message);
+        }
+    }
+    dump
+}
+
+/// Gives DM the string from [`debug_dump`]. Like the other hooks, this has to be manually hooked, e.g.
+/// ```
+/// # use auxtools::*;
+/// #[hook("/proc/callback_debug_dump")]
+/// fn _callback_debug_dump() {
+///     auxcallback::debug_dump_hook()
+/// }
+/// ```
+pub fn debug_dump_hook() -> DMResult {
+    Value::from_string(debug_dump())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_info_has_the_version() {
+        let info = version_info();
+        assert!(info.starts_with("auxcallback "));
+        assert!(info.contains(env!("CARGO_PKG_VERSION")));
+        assert_eq!(info.contains("metrics"), cfg!(feature = "metrics"));
+    }
+
+    #[test]
+    fn hook_limits() {
+        assert_eq!(HookLimit::from_millis(f32::NAN), HookLimit::Invalid);
+        assert_eq!(HookLimit::from_millis(-1.0), HookLimit::Invalid);
+        assert_eq!(
+            HookLimit::from_millis(f32::NEG_INFINITY),
+            HookLimit::Invalid
+        );
+        assert_eq!(HookLimit::from_millis(0.0), HookLimit::Nothing);
+        assert_eq!(HookLimit::from_millis(-0.0), HookLimit::Nothing);
+        assert_eq!(
+            HookLimit::from_millis(2.5),
+            HookLimit::For(Duration::from_micros(2500))
+        );
+        assert_eq!(
+            HookLimit::from_millis(500.0),
+            HookLimit::For(Duration::from_millis(500))
+        );
+        assert_eq!(HookLimit::from_millis(f32::INFINITY), HookLimit::Unlimited);
+        assert_eq!(HookLimit::from_millis(f32::MAX), HookLimit::Unlimited);
+    }
+
+    #[test]
+    fn counts_that_agree() {
+        assert_eq!(check_counts(0, 0, 0, 0), Ok(()));
+        assert_eq!(check_counts(5, 3, 96, 2), Ok(()));
+        // Callbacks that aren't sent through this crate's submit functions are queued but not tracked.
+        assert_eq!(check_counts(5, 0, 0, 0), Ok(()));
+    }
+
+    #[test]
+    fn counts_that_dont() {
+        assert!(check_counts(2, 3, 96, 0).is_err());
+        assert!(check_counts(2, 0, 96, 0).is_err());
+        assert!(check_counts(2, 2, 96, 3).is_err());
+    }
+}
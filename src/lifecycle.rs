@@ -0,0 +1,68 @@
+use auxtools::DMResult;
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering::Relaxed},
+    RwLock,
+};
+
+type Observer = Box<dyn Fn() + Send + Sync>;
+type ResultObserver = Box<dyn Fn(&DMResult) + Send + Sync>;
+
+// Checked first, so there's no locking at all until an observer's been set.
+static OBSERVED: AtomicBool = AtomicBool::new(false);
+
+static ON_SUBMIT: RwLock<Option<Observer>> = RwLock::new(None);
+static ON_BEFORE_RUN: RwLock<Option<Observer>> = RwLock::new(None);
+static ON_AFTER_RUN: RwLock<Option<ResultObserver>> = RwLock::new(None);
+
+/// Calls `observer` every time a callback is submitted through this crate's submit functions, on whichever
+/// thread submitted it. Only once it's been taken; submits that fail don't count. Setting another one replaces it.
+pub fn on_submit(observer: impl Fn() + Send + Sync + 'static) {
+    *ON_SUBMIT.write().unwrap() = Some(Box::new(observer));
+    OBSERVED.store(true, Relaxed);
+}
+
+/// Calls `observer` right before each callback is run by one of the processing functions.
+pub fn on_before_run(observer: impl Fn() + Send + Sync + 'static) {
+    *ON_BEFORE_RUN.write().unwrap() = Some(Box::new(observer));
+    OBSERVED.store(true, Relaxed);
+}
+
+/// Calls `observer` with each callback's result right after it's been run by one of the processing functions,
+/// before any error's reported.
+pub fn on_after_run(observer: impl Fn(&DMResult) + Send + Sync + 'static) {
+    *ON_AFTER_RUN.write().unwrap() = Some(Box::new(observer));
+    OBSERVED.store(true, Relaxed);
+}
+
+/// Removes all the observers.
+pub fn clear_lifecycle_observers() {
+    OBSERVED.store(false, Relaxed);
+    *ON_SUBMIT.write().unwrap() = None;
+    *ON_BEFORE_RUN.write().unwrap() = None;
+    *ON_AFTER_RUN.write().unwrap() = None;
+}
+
+pub(crate) fn submitted() {
+    if OBSERVED.load(Relaxed) {
+        if let Some(observer) = &*ON_SUBMIT.read().unwrap() {
+            observer();
+        }
+    }
+}
+
+pub(crate) fn before_run() {
+    if OBSERVED.load(Relaxed) {
+        if let Some(observer) = &*ON_BEFORE_RUN.read().unwrap() {
+            observer();
+        }
+    }
+}
+
+pub(crate) fn after_run(result: &DMResult) {
+    if OBSERVED.load(Relaxed) {
+        if let Some(observer) = &*ON_AFTER_RUN.read().unwrap() {
+            observer(result);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::cell::Cell;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::{
+    begin_processing, left_over, pending_count, process_all_until, stack_trace_proc, ErrorReporter,
+};
+
+struct Bucket {
+    per_second: f64,
+    cap: f64,
+    credits: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    // Adds what's built up since it was last refilled, giving back how many whole callbacks' worth there is.
+    fn refill(&mut self, now: Instant) -> u64 {
+        let refill = now.duration_since(self.refilled).as_secs_f64() * self.per_second;
+        self.credits = (self.credits + refill).min(self.cap);
+        self.refilled = now;
+        self.credits.floor() as u64
+    }
+
+    fn spend(&mut self, ran: u64) {
+        self.credits = (self.credits - ran as f64).max(0.0);
+    }
+}
+
+static BUCKET: Mutex<Option<Bucket>> = Mutex::new(None);
+
+/// Lets [`process_callbacks_metered`] run `per_second` callbacks a second on average, however often it's called.
+/// Credit that isn't used piles up, to at most `cap` callbacks' worth, so a quiet stretch can be made up for later.
+/// Starts out with no credit.
+pub fn set_metered_rate(per_second: f64, cap: f64) {
+    *BUCKET.lock().unwrap() = Some(Bucket {
+        per_second: per_second.max(0.0),
+        cap: cap.max(0.0),
+        credits: 0.0,
+        refilled: Instant::now(),
+    });
+}
+
+/// Runs callbacks for as long as there's credit for them, going by [`set_metered_rate`], one credit a callback.
+/// Runs everything if no rate's been set. Returns whether there were callbacks left over.
+pub fn process_callbacks_metered() -> bool {
+    let credits = match BUCKET.lock().unwrap().as_mut() {
+        Some(bucket) => bucket.refill(Instant::now()),
+        None => u64::MAX,
+    };
+    if credits == 0 {
+        return left_over();
+    }
+    let _processing = match begin_processing() {
+        Some(processing) => processing,
+        None => return pending_count() > 0,
+    };
+    let ran = Cell::new(0u64);
+    let out_of_credit = || {
+        ran.set(ran.get() + 1);
+        ran.get() >= credits
+    };
+    let stopped = process_all_until(&out_of_credit, &mut ErrorReporter::new(stack_trace_proc()));
+    if let Some(bucket) = BUCKET.lock().unwrap().as_mut() {
+        bucket.spend(ran.get());
+    }
+    stopped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn credits_build_up_to_the_cap() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            per_second: 10.0,
+            cap: 25.0,
+            credits: 0.0,
+            refilled: start,
+        };
+        assert_eq!(bucket.refill(start), 0);
+        // Partial credit carries over until it makes up a whole callback.
+        assert_eq!(bucket.refill(start + Duration::from_millis(150)), 1);
+        assert_eq!(bucket.refill(start + Duration::from_millis(250)), 2);
+        bucket.spend(2);
+        assert_eq!(bucket.refill(start + Duration::from_millis(300)), 1);
+        bucket.spend(1);
+        assert_eq!(bucket.refill(start + Duration::from_millis(320)), 0);
+        assert_eq!(bucket.refill(start + Duration::from_secs(60)), 25);
+        // Running more than there was credit for, which the last callback can do, doesn't go into debt.
+        bucket.spend(30);
+        assert_eq!(bucket.credits, 0.0);
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Both in nanoseconds; a PER of 0 means there's no quota.
+static MAX: AtomicU64 = AtomicU64::new(0);
+static PER: AtomicU64 = AtomicU64::new(0);
+// When the current window started, and how long callbacks have run for in it.
+static WINDOW: Mutex<Option<(Instant, Duration)>> = Mutex::new(None);
+
+/// Limits callbacks to running for `max` out of every `per`, e.g. 5 milliseconds every 100, no matter how many
+/// processing calls that's spread over. Once it's used up, processing stops until the next window starts.
+/// For keeping callbacks from hogging the server over time, where a budget only covers a single tick.
+/// A zero `per`, the default, turns it off.
+pub fn set_cpu_quota(max: Duration, per: Duration) {
+    MAX.store(max.as_nanos().min(u64::MAX as u128) as u64, Relaxed);
+    PER.store(per.as_nanos().min(u64::MAX as u128) as u64, Relaxed);
+    *WINDOW.lock().unwrap() = None;
+}
+
+// Gives back the time used in the current window, starting a new one if it's time.
+fn current_window(
+    window: &mut Option<(Instant, Duration)>,
+    per: u64,
+    now: Instant,
+) -> &mut Duration {
+    match window {
+        Some((started, _)) if now.duration_since(*started) < Duration::from_nanos(per) => {}
+        _ => *window = Some((now, Duration::default())),
+    }
+    &mut window.as_mut().unwrap().1
+}
+
+pub(crate) fn record(time: Duration) {
+    let per = PER.load(Relaxed);
+    if per != 0 {
+        *current_window(&mut WINDOW.lock().unwrap(), per, Instant::now()) += time;
+    }
+}
+
+pub(crate) fn exhausted() -> bool {
+    let per = PER.load(Relaxed);
+    per != 0
+        && *current_window(&mut WINDOW.lock().unwrap(), per, Instant::now())
+            >= Duration::from_nanos(MAX.load(Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_starts_over() {
+        let start = Instant::now();
+        let per = Duration::from_millis(100).as_nanos() as u64;
+        let mut window = None;
+        *current_window(&mut window, per, start) += Duration::from_millis(3);
+        *current_window(&mut window, per, start + Duration::from_millis(99)) +=
+            Duration::from_millis(4);
+        assert_eq!(
+            *current_window(&mut window, per, start + Duration::from_millis(99)),
+            Duration::from_millis(7)
+        );
+        let later = start + Duration::from_millis(100);
+        assert_eq!(*current_window(&mut window, per, later), Duration::ZERO);
+        assert_eq!(window.unwrap().0, later);
+    }
+
+    #[test]
+    fn used_up() {
+        set_cpu_quota(Duration::from_millis(10), Duration::from_secs(3600));
+        record(Duration::from_millis(6));
+        assert!(!exhausted());
+        record(Duration::from_millis(4));
+        assert!(exhausted());
+        set_cpu_quota(Duration::ZERO, Duration::ZERO);
+        record(Duration::from_secs(1));
+        assert!(!exhausted());
+    }
+}
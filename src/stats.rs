@@ -0,0 +1,630 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static OVERRUN_COUNT: AtomicU64 = AtomicU64::new(0);
+static REMAINING_AT_OVERRUN: AtomicUsize = AtomicUsize::new(0);
+static QUEUED_BYTES: AtomicUsize = AtomicUsize::new(0);
+// Ages are in nanoseconds.
+static MAX_AGE: AtomicU64 = AtomicU64::new(0);
+static TOTAL_AGE: AtomicU64 = AtomicU64::new(0);
+static AGED_CALLBACKS: AtomicU64 = AtomicU64::new(0);
+// How many tracked callbacks are still in the queue.
+static TRACKED_PENDING: AtomicUsize = AtomicUsize::new(0);
+// When each of them was submitted, by the order they were submitted in. This takes a lock on every submit and
+// run, so it's only kept with the pending_age feature.
+static PENDING_SINCE: Mutex<BTreeMap<u64, Instant>> = Mutex::new(BTreeMap::new());
+static NEXT_PENDING: AtomicU64 = AtomicU64::new(0);
+// When a processing call last got to run anything, or the library was loaded if none has yet.
+static LAST_PROCESSED: Mutex<Option<Instant>> = Mutex::new(None);
+static STALE_CALLBACKS: AtomicU64 = AtomicU64::new(0);
+static ERROR_COUNT: AtomicU64 = AtomicU64::new(0);
+// In nanoseconds.
+static BLOCKED_TIME: AtomicU64 = AtomicU64::new(0);
+// These two are f64s, stored as their bits. The default alpha is 0.2.
+static THROUGHPUT: AtomicU64 = AtomicU64::new(0);
+static THROUGHPUT_ALPHA: AtomicU64 = AtomicU64::new(0x3FC999999999999A);
+
+// Without the metrics feature, nothing's recorded and everything stays at zero.
+const ENABLED: bool = cfg!(feature = "metrics");
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static DEPTH_HISTOGRAM: [AtomicU64; 5] = [ZERO; 5];
+// Callback run times, and how long they waited to be run.
+static LATENCY_HISTOGRAM: DurationHistogram = DurationHistogram::new();
+static QUEUE_LATENCY_HISTOGRAM: DurationHistogram = DurationHistogram::new();
+
+// Bucket n counts durations of less than 2^n nanoseconds, and at least 2^(n-1).
+struct DurationHistogram([AtomicU64; 64]);
+
+impl DurationHistogram {
+    const fn new() -> Self {
+        Self([ZERO; 64])
+    }
+
+    fn record(&self, duration: Duration) {
+        let nanos = duration.as_nanos().min(u64::MAX as u128) as u64;
+        let bucket = (64 - nanos.leading_zeros() as usize).min(63);
+        self.0[bucket].fetch_add(1, Relaxed);
+    }
+
+    fn percentile(&self, p: f64) -> Option<Duration> {
+        let counts: Vec<u64> = self.0.iter().map(|bucket| bucket.load(Relaxed)).collect();
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let wanted = ((p.clamp(0.0, 1.0) * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= wanted {
+                return Some(Duration::from_nanos(1u64 << bucket));
+            }
+        }
+        None
+    }
+}
+
+/// A snapshot of how callback processing has been going.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallbackStats {
+    /// How many times a time limited processing call ran out of time with callbacks still queued.
+    /// If this keeps going up, the time limit or the capacity is too small.
+    pub overrun_count: u64,
+    /// How many callbacks were still queued the last time that happened.
+    pub remaining_at_overrun: usize,
+    /// See [`depth_histogram`].
+    pub depth_histogram: [u64; 5],
+    /// See [`queued_bytes`].
+    pub queued_bytes: usize,
+    /// See [`max_age`].
+    pub max_age: Duration,
+    /// See [`avg_age`].
+    pub avg_age: Duration,
+    /// See [`stale_callbacks`].
+    pub stale_callbacks: u64,
+    /// See [`error_count`].
+    pub error_count: u64,
+    /// See [`blocked_time`].
+    pub blocked_time: Duration,
+    /// See [`oldest_pending_age`].
+    pub oldest_pending_age: Option<Duration>,
+}
+
+/// Gets the current stats. These are only kept with the `metrics` feature, which is on by default;
+/// without it, they're all zero, and submitting and processing don't pay for keeping them.
+pub fn stats() -> CallbackStats {
+    CallbackStats {
+        overrun_count: OVERRUN_COUNT.load(Relaxed),
+        remaining_at_overrun: REMAINING_AT_OVERRUN.load(Relaxed),
+        depth_histogram: depth_histogram(),
+        queued_bytes: queued_bytes(),
+        max_age: max_age(),
+        avg_age: avg_age(),
+        stale_callbacks: stale_callbacks(),
+        error_count: error_count(),
+        blocked_time: blocked_time(),
+        oldest_pending_age: oldest_pending_age(),
+    }
+}
+
+/// Same as [`stats`], but resets the counters as it goes, so each call gives what's happened since the last one.
+/// For scrapers that want deltas. Things that aren't counters, like [`queued_bytes`], are left alone, and
+/// [`max_age`] starts over.
+pub fn take_stats() -> CallbackStats {
+    let mut depth_histogram = [0; 5];
+    for (count, bucket) in depth_histogram.iter_mut().zip(DEPTH_HISTOGRAM.iter()) {
+        *count = bucket.swap(0, Relaxed);
+    }
+    let total_age = TOTAL_AGE.swap(0, Relaxed);
+    let avg_age = match AGED_CALLBACKS.swap(0, Relaxed) {
+        0 => Duration::default(),
+        count => Duration::from_nanos(total_age / count),
+    };
+    CallbackStats {
+        overrun_count: OVERRUN_COUNT.swap(0, Relaxed),
+        remaining_at_overrun: REMAINING_AT_OVERRUN.load(Relaxed),
+        depth_histogram,
+        queued_bytes: queued_bytes(),
+        max_age: Duration::from_nanos(MAX_AGE.swap(0, Relaxed)),
+        avg_age,
+        stale_callbacks: STALE_CALLBACKS.swap(0, Relaxed),
+        error_count: ERROR_COUNT.swap(0, Relaxed),
+        blocked_time: Duration::from_nanos(BLOCKED_TIME.swap(0, Relaxed)),
+        oldest_pending_age: oldest_pending_age(),
+    }
+}
+
+/// How long the queue was at the start of each processing call, bucketed into
+/// 0, 1-10, 11-100, 101-1000 and more than 1000 callbacks.
+pub fn depth_histogram() -> [u64; 5] {
+    let mut histogram = [0; 5];
+    for (count, bucket) in histogram.iter_mut().zip(DEPTH_HISTOGRAM.iter()) {
+        *count = bucket.load(Relaxed);
+    }
+    histogram
+}
+
+pub(crate) fn record_depth(depth: usize) {
+    if !ENABLED {
+        return;
+    }
+    let bucket = match depth {
+        0 => 0,
+        1..=10 => 1,
+        11..=100 => 2,
+        101..=1000 => 3,
+        _ => 4,
+    };
+    DEPTH_HISTOGRAM[bucket].fetch_add(1, Relaxed);
+}
+
+pub(crate) fn record_overrun(remaining: usize) {
+    if !ENABLED {
+        return;
+    }
+    OVERRUN_COUNT.fetch_add(1, Relaxed);
+    REMAINING_AT_OVERRUN.store(remaining, Relaxed);
+}
+
+/// Roughly how much memory the closures of queued callbacks are taking up, going by the size of what they captured.
+/// Only counts callbacks sent through this crate's submit functions.
+pub fn queued_bytes() -> usize {
+    QUEUED_BYTES.load(Relaxed)
+}
+
+/// Counts its bytes as queued for as long as it's alive. Put in a callback, that's until the callback is dropped.
+pub(crate) struct QueuedBytes(usize);
+
+impl QueuedBytes {
+    pub(crate) fn new(bytes: usize) -> Self {
+        let bytes = if ENABLED { bytes } else { 0 };
+        if bytes != 0 {
+            QUEUED_BYTES.fetch_add(bytes, Relaxed);
+        }
+        Self(bytes)
+    }
+}
+
+impl Drop for QueuedBytes {
+    fn drop(&mut self) {
+        if self.0 != 0 {
+            QUEUED_BYTES.fetch_sub(self.0, Relaxed);
+        }
+    }
+}
+
+/// How long the oldest callback that's still queued has been waiting. If this keeps growing, processing isn't
+/// getting through the queue. None if there's nothing queued. Only counts callbacks sent through this crate's
+/// submit functions. Keeping track of this costs a lock on every submit and run, so it needs the `pending_age`
+/// feature as well as `metrics`, and is always None without it.
+pub fn oldest_pending_age() -> Option<Duration> {
+    PENDING_SINCE
+        .lock()
+        .unwrap()
+        .values()
+        .next()
+        .map(|submitted| submitted.elapsed())
+}
+
+pub(crate) fn tracked_pending() -> usize {
+    TRACKED_PENDING.load(Relaxed)
+}
+
+const PENDING_AGE: bool = ENABLED && cfg!(feature = "pending_age");
+
+/// Counts as pending since it was made, for as long as it's alive. Put in a callback, that's until it's dropped.
+pub(crate) struct PendingSince {
+    tracked: bool,
+    id: Option<u64>,
+}
+
+impl PendingSince {
+    pub(crate) fn new() -> Self {
+        if !ENABLED {
+            return Self {
+                tracked: false,
+                id: None,
+            };
+        }
+        TRACKED_PENDING.fetch_add(1, Relaxed);
+        let id = PENDING_AGE.then(|| {
+            let id = NEXT_PENDING.fetch_add(1, Relaxed);
+            PENDING_SINCE.lock().unwrap().insert(id, Instant::now());
+            id
+        });
+        Self { tracked: true, id }
+    }
+}
+
+impl Drop for PendingSince {
+    fn drop(&mut self) {
+        if self.tracked {
+            TRACKED_PENDING.fetch_sub(1, Relaxed);
+        }
+        if let Some(id) = self.id {
+            PENDING_SINCE.lock().unwrap().remove(&id);
+        }
+    }
+}
+
+/// The longest a callback has waited between being submitted and being run.
+/// High ages mean processing is falling behind. Only counts callbacks sent through this crate's submit functions.
+pub fn max_age() -> Duration {
+    Duration::from_nanos(MAX_AGE.load(Relaxed))
+}
+
+/// The average time callbacks have waited between being submitted and being run.
+/// Only counts callbacks sent through this crate's submit functions.
+pub fn avg_age() -> Duration {
+    match AGED_CALLBACKS.load(Relaxed) {
+        0 => Duration::default(),
+        count => Duration::from_nanos(TOTAL_AGE.load(Relaxed) / count),
+    }
+}
+
+pub(crate) fn record_age(age: Duration) {
+    if !ENABLED {
+        return;
+    }
+    QUEUE_LATENCY_HISTOGRAM.record(age);
+    let nanos = age.as_nanos() as u64;
+    MAX_AGE.fetch_max(nanos, Relaxed);
+    TOTAL_AGE.fetch_add(nanos, Relaxed);
+    AGED_CALLBACKS.fetch_add(1, Relaxed);
+}
+
+/// How many callbacks sent with [`submit_while_alive`](super::submit_while_alive) were skipped because their datum
+/// was deleted first.
+pub fn stale_callbacks() -> u64 {
+    STALE_CALLBACKS.load(Relaxed)
+}
+
+pub(crate) fn record_stale() {
+    if !ENABLED {
+        return;
+    }
+    STALE_CALLBACKS.fetch_add(1, Relaxed);
+}
+
+/// How many callbacks have returned an error, whether or not it was reported.
+pub fn error_count() -> u64 {
+    ERROR_COUNT.load(Relaxed)
+}
+
+pub(crate) fn record_error() {
+    if !ENABLED {
+        return;
+    }
+    ERROR_COUNT.fetch_add(1, Relaxed);
+}
+
+/// The total time threads have spent in [`submit_blocking`](super::submit_blocking) waiting for room in a full
+/// queue. If this keeps going up, the queue isn't being processed fast enough to keep up.
+pub fn blocked_time() -> Duration {
+    Duration::from_nanos(BLOCKED_TIME.load(Relaxed))
+}
+
+pub(crate) fn record_blocked(time: Duration) {
+    if !ENABLED {
+        return;
+    }
+    BLOCKED_TIME.fetch_add(time.as_nanos() as u64, Relaxed);
+}
+
+pub(crate) fn record_processed() {
+    *LAST_PROCESSED.lock().unwrap() = Some(Instant::now());
+}
+
+pub(crate) fn since_processed() -> Option<Duration> {
+    LAST_PROCESSED.lock().unwrap().map(|last| last.elapsed())
+}
+
+/// How long callbacks take to run at the `p`th percentile, `p` being from 0 to 1, e.g. 0.99 for the time that
+/// 99% of callbacks finish within. This is rounded up to a power of two nanoseconds, so it's only ever within
+/// double of the real thing, but that's plenty for spotting the slow tail. None if nothing's been run yet.
+pub fn latency_percentile(p: f64) -> Option<Duration> {
+    LATENCY_HISTOGRAM.percentile(p)
+}
+
+pub(crate) fn record_latency(latency: Duration) {
+    if !ENABLED {
+        return;
+    }
+    LATENCY_HISTOGRAM.record(latency);
+}
+
+/// Same as [`latency_percentile`], but for how long callbacks waited between being submitted and being run,
+/// which is what decides how responsive things feel. Only counts callbacks sent through this crate's submit
+/// functions.
+pub fn queue_latency_percentile(p: f64) -> Option<Duration> {
+    QUEUE_LATENCY_HISTOGRAM.percentile(p)
+}
+
+/// Roughly how many callbacks get run per second of processing, smoothed out over processing calls so one slow
+/// tick doesn't throw it off. Zero until something's been processed.
+pub fn throughput() -> f64 {
+    f64::from_bits(THROUGHPUT.load(Relaxed))
+}
+
+/// How much each processing call counts towards [`throughput`], between 0 and 1. Higher follows changes faster,
+/// lower smooths out more. Defaults to 0.2.
+pub fn set_throughput_alpha(alpha: f64) {
+    THROUGHPUT_ALPHA.store(alpha.clamp(0.0, 1.0).to_bits(), Relaxed);
+}
+
+pub(crate) fn record_pass(callbacks: usize, elapsed: Duration) {
+    if !ENABLED {
+        return;
+    }
+    if callbacks == 0 || elapsed.is_zero() {
+        return;
+    }
+    let rate = callbacks as f64 / elapsed.as_secs_f64();
+    let alpha = f64::from_bits(THROUGHPUT_ALPHA.load(Relaxed));
+    let _ = THROUGHPUT.fetch_update(Relaxed, Relaxed, |old| {
+        let old = f64::from_bits(old);
+        let new = if old == 0.0 {
+            rate
+        } else {
+            alpha * rate + (1.0 - alpha) * old
+        };
+        Some(new.to_bits())
+    });
+}
+
+fn push_metric_header(text: &mut String, name: &str, kind: &str, help: &str) {
+    text.push_str(&format!("# HELP auxcallback_{} {}\n", name, help));
+    text.push_str(&format!("# TYPE auxcallback_{} {}\n", name, kind));
+}
+
+fn push_metric(text: &mut String, name: &str, kind: &str, help: &str, value: impl ToString) {
+    push_metric_header(text, name, kind, help);
+    text.push_str(&format!("auxcallback_{} {}\n", name, value.to_string()));
+}
+
+/// All the stats in Prometheus' text format, ready to be served up to whatever's scraping them.
+pub fn metrics_text() -> String {
+    let stats = stats();
+    let mut text = String::new();
+    push_metric(
+        &mut text,
+        "pending",
+        "gauge",
+        "Callbacks waiting to be processed.",
+        // Scrapers can ask before the library's loaded, or after it's shut down.
+        if super::channels_open() {
+            super::pending_count()
+        } else {
+            0
+        },
+    );
+    push_metric(
+        &mut text,
+        "overruns_total",
+        "counter",
+        "Time limited processing calls that ran out of time with callbacks left.",
+        stats.overrun_count,
+    );
+    push_metric(
+        &mut text,
+        "remaining_at_overrun",
+        "gauge",
+        "Callbacks left the last time processing ran out of time.",
+        stats.remaining_at_overrun,
+    );
+    push_metric(
+        &mut text,
+        "queued_bytes",
+        "gauge",
+        "Approximate memory used by queued callbacks.",
+        stats.queued_bytes,
+    );
+    push_metric(
+        &mut text,
+        "max_age_seconds",
+        "gauge",
+        "Longest a callback has waited to be run.",
+        stats.max_age.as_secs_f64(),
+    );
+    push_metric(
+        &mut text,
+        "avg_age_seconds",
+        "gauge",
+        "Average time callbacks have waited to be run.",
+        stats.avg_age.as_secs_f64(),
+    );
+    push_metric(
+        &mut text,
+        "stale_total",
+        "counter",
+        "Callbacks skipped because their datum was deleted first.",
+        stats.stale_callbacks,
+    );
+    push_metric(
+        &mut text,
+        "errors_total",
+        "counter",
+        "Callbacks that returned an error.",
+        stats.error_count,
+    );
+    push_metric(
+        &mut text,
+        "blocked_seconds_total",
+        "counter",
+        "Time spent waiting to submit to a full queue.",
+        stats.blocked_time.as_secs_f64(),
+    );
+    push_metric(
+        &mut text,
+        "oldest_pending_age_seconds",
+        "gauge",
+        "How long the oldest queued callback has been waiting.",
+        stats.oldest_pending_age.unwrap_or_default().as_secs_f64(),
+    );
+    push_metric(
+        &mut text,
+        "throughput",
+        "gauge",
+        "Smoothed callbacks run per second of processing.",
+        throughput(),
+    );
+    push_metric_header(
+        &mut text,
+        "depth_samples_total",
+        "counter",
+        "Processing calls by how many callbacks were queued at the start.",
+    );
+    let buckets = ["0", "1-10", "11-100", "101-1000", "1001+"];
+    for (bucket, count) in buckets.iter().zip(stats.depth_histogram.iter()) {
+        text.push_str(&format!(
+            "auxcallback_depth_samples_total{{depth=\"{}\"}} {}\n",
+            bucket, count
+        ));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // take_stats resets the depth histogram, which depth_bucket_edges is watching.
+    #[cfg(feature = "metrics")]
+    static TAKE_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn histogram_buckets() {
+        let histogram = DurationHistogram::new();
+        histogram.record(Duration::ZERO);
+        histogram.record(Duration::from_nanos(1));
+        histogram.record(Duration::from_nanos(127));
+        histogram.record(Duration::from_nanos(128));
+        histogram.record(Duration::MAX);
+        let counts: Vec<u64> = histogram
+            .0
+            .iter()
+            .map(|bucket| bucket.load(Relaxed))
+            .collect();
+        assert_eq!(counts[0], 1);
+        assert_eq!(counts[1], 1);
+        assert_eq!(counts[7], 1);
+        assert_eq!(counts[8], 1);
+        assert_eq!(counts[63], 1);
+        assert_eq!(counts.iter().sum::<u64>(), 5);
+    }
+
+    #[test]
+    fn histogram_percentiles() {
+        let histogram = DurationHistogram::new();
+        assert_eq!(histogram.percentile(0.5), None);
+        for _ in 0..90 {
+            histogram.record(Duration::from_nanos(100));
+        }
+        for _ in 0..10 {
+            histogram.record(Duration::from_micros(10));
+        }
+        // Each comes out as the power of two just above it.
+        let fast = Some(Duration::from_nanos(128));
+        let slow = Some(Duration::from_nanos(16384));
+        assert_eq!(histogram.percentile(0.0), fast);
+        assert_eq!(histogram.percentile(0.5), fast);
+        assert_eq!(histogram.percentile(0.9), fast);
+        assert_eq!(histogram.percentile(0.91), slow);
+        assert_eq!(histogram.percentile(0.99), slow);
+        assert_eq!(histogram.percentile(1.0), slow);
+        assert_eq!(histogram.percentile(2.0), slow);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn depth_bucket_edges() {
+        let _lock = TAKE_LOCK.lock().unwrap();
+        let before = depth_histogram();
+        for depth in [0, 1, 10, 11, 100, 101, 1000, 1001, usize::MAX] {
+            record_depth(depth);
+        }
+        let after = depth_histogram();
+        let added: Vec<u64> = after
+            .iter()
+            .zip(before.iter())
+            .map(|(a, b)| a - b)
+            .collect();
+        assert_eq!(added, [1, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn throughput_smoothing() {
+        THROUGHPUT.store(0, Relaxed);
+        set_throughput_alpha(0.5);
+        // The first pass is taken as is, rather than averaged in with nothing.
+        record_pass(100, Duration::from_secs(1));
+        assert_eq!(throughput(), 100.0);
+        record_pass(100, Duration::from_millis(500));
+        assert_eq!(throughput(), 150.0);
+        // Passes that ran nothing, or took no time, don't say anything about the rate.
+        record_pass(0, Duration::from_secs(1));
+        record_pass(100, Duration::ZERO);
+        assert_eq!(throughput(), 150.0);
+        set_throughput_alpha(2.0);
+        record_pass(10, Duration::from_secs(1));
+        assert_eq!(throughput(), 10.0);
+        set_throughput_alpha(0.2);
+    }
+
+    #[test]
+    #[cfg(feature = "metrics")]
+    fn taken_stats_dont_overlap() {
+        let _lock = TAKE_LOCK.lock().unwrap();
+        take_stats();
+        record_error();
+        record_stale();
+        record_overrun(5);
+        let first = take_stats();
+        record_error();
+        record_error();
+        let second = take_stats();
+        assert_eq!(
+            (
+                first.error_count,
+                first.stale_callbacks,
+                first.overrun_count
+            ),
+            (1, 1, 1)
+        );
+        assert_eq!(
+            (
+                second.error_count,
+                second.stale_callbacks,
+                second.overrun_count
+            ),
+            (2, 0, 0)
+        );
+        assert_eq!(take_stats().error_count, 0);
+    }
+
+    #[test]
+    fn metrics_format() {
+        let text = metrics_text();
+        let mut typed = Vec::new();
+        for line in text.lines() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert!(kind == "gauge" || kind == "counter", "{}", line);
+                typed.push(name.to_owned());
+            } else if !line.starts_with("# HELP auxcallback_") {
+                let (name, value) = line.rsplit_once(' ').unwrap();
+                let name = name.split('{').next().unwrap();
+                assert_eq!(typed.last().map(String::as_str), Some(name), "{}", line);
+                value.parse::<f64>().unwrap();
+            }
+        }
+        assert!(typed.iter().all(|name| name.starts_with("auxcallback_")));
+        assert!(text.contains("auxcallback_pending 0\n"));
+        assert!(text.contains("auxcallback_depth_samples_total{depth=\"1001+\"} "));
+    }
+}
@@ -0,0 +1,40 @@
+use auxtools::DMResult;
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering::Relaxed},
+    Mutex,
+};
+
+type ResultSender = flume::Sender<Result<(), String>>;
+
+static PUBLISHING: AtomicBool = AtomicBool::new(false);
+
+static RESULT_SENDER: Mutex<Option<ResultSender>> = Mutex::new(None);
+
+/// Gives you a stream of the outcome of every callback processed from now on, for monitoring.
+/// Nothing is published until this is called, and only the most recently created stream gets results;
+/// making a new one ends the old one. If the stream falls too far behind, results are dropped rather than
+/// holding up processing.
+pub fn result_stream() -> impl futures_core::Stream<Item = Result<(), String>> {
+    let (sender, receiver) = flume::bounded(100000);
+    *RESULT_SENDER.lock().unwrap() = Some(sender);
+    PUBLISHING.store(true, Relaxed);
+    receiver.into_stream()
+}
+
+pub(crate) fn publish_result(result: &DMResult) {
+    if !PUBLISHING.load(Relaxed) {
+        return;
+    }
+    let mut sender = RESULT_SENDER.lock().unwrap();
+    let outcome = match result {
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.message.clone()),
+    };
+    if let Some(Err(flume::TrySendError::Disconnected(_))) =
+        sender.as_ref().map(|s| s.try_send(outcome))
+    {
+        *sender = None;
+        PUBLISHING.store(false, Relaxed);
+    }
+}
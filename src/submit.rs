@@ -0,0 +1,749 @@
+use auxtools::*;
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::held;
+use super::lifecycle;
+use super::stats::{self, PendingSince, QueuedBytes};
+use super::{byond_callback_sender, channels_open, critical_sender, overflow_sender, DeferredFunc};
+
+thread_local! {
+    static ON_GAME_THREAD: Cell<bool> = const { Cell::new(false) };
+    static STOP_REQUESTED: Cell<bool> = const { Cell::new(false) };
+    static LAST_COST: Cell<Option<u32>> = const { Cell::new(None) };
+    static EXTENSION_REQUESTED: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+    static PROCESSING_DEPTH: Cell<usize> = const { Cell::new(0) };
+    // How many of those processing calls have to run regardless of the CPU quota.
+    static FORCED_DEPTH: Cell<usize> = const { Cell::new(0) };
+    // Callbacks submitted from inside other callbacks while the queue was full.
+    static DEFERRED: RefCell<VecDeque<DeferredFunc>> = const { RefCell::new(VecDeque::new()) };
+    static DEFERRED_THIS_CALL: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Sets whether the current thread counts as the game thread, for [`submit_or_run`].
+/// This is done for you when the library is initialized and whenever callbacks are processed,
+/// so you should only need it if you're doing something odd.
+pub fn mark_game_thread(on_game_thread: bool) {
+    ON_GAME_THREAD.with(|flag| flag.set(on_game_thread));
+}
+
+pub(crate) fn on_game_thread() -> bool {
+    ON_GAME_THREAD.with(|flag| flag.get())
+}
+
+/// Called from inside a callback, this makes the processing call that's running it stop once it returns,
+/// leaving the rest of the queue for next time. For a callback that finds whatever it's working on isn't ready yet.
+/// Outside of a callback it does nothing.
+pub fn stop_processing() {
+    if in_processing() {
+        STOP_REQUESTED.with(|stop| stop.set(true));
+    }
+}
+
+pub(crate) fn take_stop_request() -> bool {
+    STOP_REQUESTED.with(|stop| stop.replace(false))
+}
+
+// In nanoseconds.
+static MAX_EXTENSION: AtomicU64 = AtomicU64::new(0);
+
+/// Caps how much extra time callbacks can ask for with [`extend_budget`] in one processing call.
+/// Zero, the default, means they can't.
+pub fn set_max_budget_extension(max: Duration) {
+    MAX_EXTENSION.store(max.as_nanos().min(u64::MAX as u128) as u64, Relaxed);
+}
+
+/// Called from inside a callback, this gives the [`process_callbacks_for`](super::process_callbacks_for) call that's
+/// running it `extra` more time, for a callback that knows there's more work right behind it. It all adds up, to
+/// at most what [`set_max_budget_extension`] allows. Outside of a callback it does nothing.
+pub fn extend_budget(extra: Duration) {
+    if in_processing() {
+        EXTENSION_REQUESTED.with(|extension| extension.set(extension.get().saturating_add(extra)));
+    }
+}
+
+// Adds any extension that's been asked for since the last check onto what's been given so far.
+pub(crate) fn take_extension(given: Duration) -> Duration {
+    let requested = EXTENSION_REQUESTED.with(|extension| extension.replace(Duration::ZERO));
+    given
+        .saturating_add(requested)
+        .min(Duration::from_nanos(MAX_EXTENSION.load(Relaxed)))
+}
+
+/// Whether this thread is currently inside one of the processing functions, i.e. if this is being called from a callback.
+///
+/// Submitting from inside a callback is fine. If the queue is full at the time, the submit functions in this crate
+/// hold onto the callback and send it once the outermost processing call finishes, rather than failing;
+/// sending straight through [`byond_callback_sender`] with a blocking `send` would wait forever, since nothing can
+/// empty the queue until the callback returns. Processing from inside a callback also works, it just carries on
+/// through the same queue.
+pub fn in_processing() -> bool {
+    processing_depth() > 0
+}
+
+pub(crate) fn processing_depth() -> usize {
+    PROCESSING_DEPTH.with(|depth| depth.get())
+}
+
+pub(crate) fn processing_forced() -> bool {
+    FORCED_DEPTH.with(|depth| depth.get()) > 0
+}
+
+/// Held for the duration of a processing call.
+pub(crate) struct ProcessingScope {
+    forced: bool,
+}
+
+impl ProcessingScope {
+    /// For a processing call that has to get through everything, like at shutdown, so the CPU quota doesn't
+    /// stop it partway. That goes for anything processed from inside it too.
+    pub(crate) fn enter_forced() -> Self {
+        let mut scope = Self::enter();
+        FORCED_DEPTH.with(|depth| depth.set(depth.get() + 1));
+        scope.forced = true;
+        scope
+    }
+
+    pub(crate) fn enter() -> Self {
+        let outermost = PROCESSING_DEPTH.with(|depth| {
+            depth.set(depth.get() + 1);
+            depth.get() == 1
+        });
+        if outermost {
+            DEFERRED_THIS_CALL.with(|deferred| deferred.set(0));
+            // A stop asked for during a call that didn't check for it, like step, is over with since that call is.
+            STOP_REQUESTED.with(|stop| stop.set(false));
+        }
+        Self { forced: false }
+    }
+}
+
+impl Drop for ProcessingScope {
+    fn drop(&mut self) {
+        if self.forced {
+            FORCED_DEPTH.with(|depth| depth.set(depth.get() - 1));
+        }
+        let depth = PROCESSING_DEPTH.with(|depth| {
+            depth.set(depth.get() - 1);
+            depth.get()
+        });
+        if depth == 0 {
+            // Extensions asked for by callbacks that nothing was timing would otherwise carry into the next call.
+            EXTENSION_REQUESTED.with(|extension| extension.set(Duration::ZERO));
+            flush_deferred();
+        }
+    }
+}
+
+pub(crate) fn deferred_count() -> usize {
+    DEFERRED.with(|deferred| deferred.borrow().len())
+}
+
+// Anything that still doesn't fit waits for the next processing call to finish.
+fn flush_deferred() {
+    DEFERRED.with(|deferred| {
+        let mut deferred = deferred.borrow_mut();
+        let sender = byond_callback_sender();
+        while let Some(func) = deferred.pop_front() {
+            if let Err(err) = sender.try_send(func) {
+                deferred.push_front(err.into_inner());
+                break;
+            }
+        }
+    })
+}
+
+static MAX_DEFERRED: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Limits how many callbacks submitted from inside other callbacks can be held back for later during a single
+/// processing call when the queue's full. Past that, they're refused with [`SubmitError::Full`] like they would
+/// be from anywhere else, so a callback that keeps submitting more can't pile up without end. No limit by default.
+pub fn set_max_chained_submits(max: usize) {
+    MAX_DEFERRED.store(max, Relaxed);
+}
+
+fn defer_allowed() -> bool {
+    in_processing()
+        && DEFERRED_THIS_CALL.with(|deferred| {
+            let allowed = deferred.get() < MAX_DEFERRED.load(Relaxed);
+            if allowed {
+                deferred.set(deferred.get() + 1);
+            }
+            allowed
+        })
+}
+
+static SUBMIT_RATE_LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+static SUBMITS_THIS_TICK: AtomicUsize = AtomicUsize::new(0);
+static OVERFLOW: AtomicBool = AtomicBool::new(false);
+
+/// Why a callback couldn't be submitted. Each one gives the callback back, in case you want to try again.
+/// `F` is the kind of callback, which is only something other than [`DeferredFunc`] for queues of their own,
+/// like `submit_arena`'s with the `bumpalo` feature.
+pub enum SubmitError<F = DeferredFunc> {
+    /// The queue is full.
+    Full(F),
+    /// The queue is gone, because the library hasn't been loaded yet or has been shut down.
+    /// The callback won't run unless you submit it again once it's back.
+    Disconnected(F),
+    /// More callbacks have been submitted since callbacks were last processed than the rate limit allows.
+    RateLimited(F),
+    /// A callback with the same key is already queued or running. See [`submit_keyed`](super::submit_keyed).
+    Duplicate(F),
+    /// [`begin_shutdown`] has been called, so nothing new is being taken.
+    ShuttingDown(F),
+}
+
+impl<F> SubmitError<F> {
+    /// Gets back the callback that couldn't be submitted.
+    pub fn into_inner(self) -> F {
+        match self {
+            Self::Full(func)
+            | Self::Disconnected(func)
+            | Self::RateLimited(func)
+            | Self::Duplicate(func)
+            | Self::ShuttingDown(func) => func,
+        }
+    }
+}
+
+impl<F> fmt::Debug for SubmitError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("Full(..)"),
+            Self::Disconnected(_) => f.write_str("Disconnected(..)"),
+            Self::RateLimited(_) => f.write_str("RateLimited(..)"),
+            Self::Duplicate(_) => f.write_str("Duplicate(..)"),
+            Self::ShuttingDown(_) => f.write_str("ShuttingDown(..)"),
+        }
+    }
+}
+
+impl<F> fmt::Display for SubmitError<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("callback queue is full"),
+            Self::Disconnected(_) => f.write_str("callback queue is disconnected"),
+            Self::RateLimited(_) => f.write_str("callback submit rate limit reached"),
+            Self::Duplicate(_) => {
+                f.write_str("a callback with that key is already queued or running")
+            }
+            Self::ShuttingDown(_) => f.write_str("callbacks are shutting down"),
+        }
+    }
+}
+
+impl<F> std::error::Error for SubmitError<F> {}
+
+impl<F> From<flume::TrySendError<F>> for SubmitError<F> {
+    fn from(err: flume::TrySendError<F>) -> Self {
+        match err {
+            flume::TrySendError::Full(func) => Self::Full(func),
+            flume::TrySendError::Disconnected(func) => Self::Disconnected(func),
+        }
+    }
+}
+
+/// Caps how many callbacks the submit functions in this crate will take between processing calls,
+/// rejecting anything past that with [`SubmitError::RateLimited`]. None, the default, means no limit.
+/// This doesn't apply to callbacks sent straight through [`byond_callback_sender`].
+pub fn set_submit_rate_limit(max_per_tick: Option<usize>) {
+    SUBMIT_RATE_LIMIT.store(max_per_tick.unwrap_or(usize::MAX), Relaxed);
+}
+
+pub(crate) fn reset_rate_limit() {
+    SUBMITS_THIS_TICK.store(0, Relaxed);
+}
+
+// Takes back a submit that didn't go through. The count can be reset in between, so this stops at zero rather than
+// wrapping around and rate limiting everything.
+pub(crate) fn uncount_submit() {
+    let _ = SUBMITS_THIS_TICK.fetch_update(Relaxed, Relaxed, |count| count.checked_sub(1));
+}
+
+/// If set, callbacks submitted while the queue is full go into an unbounded overflow queue instead of failing.
+/// The overflow queue is only processed once the main one is empty.
+/// This doesn't apply to callbacks sent straight through [`byond_callback_sender`]. Off by default.
+pub fn set_overflow(overflow: bool) {
+    OVERFLOW.store(overflow, Relaxed);
+}
+
+/// Sends off a callback to be processed later, without blocking if the queue is full.
+pub fn submit(func: impl Fn() -> DMResult + Send + Sync + 'static) -> Result<(), SubmitError> {
+    submit_boxed(Box::new(func))
+}
+
+static TICK_SCOPED: AtomicBool = AtomicBool::new(false);
+// Goes up at the start of every processing call that isn't inside another one.
+static TICK: AtomicU64 = AtomicU64::new(0);
+// How many callbacks stamped with each tick are still queued. Only kept while TICK_SCOPED is set.
+static STAMPED: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+
+// Counts a callback under the tick it was submitted in, for as long as it's alive.
+struct TickStamp(u64);
+
+impl TickStamp {
+    fn new() -> Self {
+        let tick = TICK.load(Relaxed);
+        *STAMPED.lock().unwrap().entry(tick).or_default() += 1;
+        Self(tick)
+    }
+}
+
+impl Drop for TickStamp {
+    fn drop(&mut self) {
+        let mut stamped = STAMPED.lock().unwrap();
+        if let Some(count) = stamped.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                stamped.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// For tracking down callbacks that are assumed to be done with by the end of the tick, but aren't. If set, every
+/// processing call warns about callbacks that were already queued when the last one started and are still
+/// there, sent to `/proc/auxtools_stack_trace` like other warnings. Only callbacks submitted through this crate's
+/// submit functions while it's on are counted. Off by default.
+pub fn set_tick_scoped(enabled: bool) {
+    TICK_SCOPED.store(enabled, Relaxed);
+}
+
+pub(crate) fn advance_tick() {
+    let tick = TICK.fetch_add(1, Relaxed);
+    if !TICK_SCOPED.load(Relaxed) {
+        return;
+    }
+    let stragglers: usize = STAMPED
+        .lock()
+        .unwrap()
+        .range(..tick)
+        .map(|(_, count)| count)
+        .sum();
+    if stragglers > 0 {
+        super::errors::report_warning(&format!(
+            "{} callbacks outlived the tick they were submitted in",
+            stragglers
+        ));
+    }
+}
+
+// Wraps up a callback with everything that needs to follow it from submission to being run: stats and its generation.
+// Whatever sends it tells the on_submit observer once it's actually gone in.
+fn track(func: DeferredFunc) -> DeferredFunc {
+    let stamp = TICK_SCOPED.load(Relaxed).then(TickStamp::new);
+    let queued = QueuedBytes::new(std::mem::size_of_val(&*func));
+    let pending = PendingSince::new();
+    let submitted = Instant::now();
+    let generation = super::handle::current_generation();
+    Box::new(move || {
+        let _queued = &queued;
+        let _pending = &pending;
+        let _stamp = &stamp;
+        stats::record_age(submitted.elapsed());
+        if super::handle::generation_cancelled(generation) {
+            return Ok(Value::null());
+        }
+        func()
+    })
+}
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Makes every submit function in this crate except [`submit_critical`] refuse new callbacks with
+/// [`SubmitError::ShuttingDown`], e.g. at round end, so threads that are still going can't keep adding work that
+/// won't get done.
+/// Undone by [`cancel_shutdown`], or when the library is loaded again.
+pub fn begin_shutdown() {
+    SHUTTING_DOWN.store(true, Relaxed);
+}
+
+pub(crate) fn shutting_down() -> bool {
+    SHUTTING_DOWN.load(Relaxed)
+}
+
+/// Lets callbacks be submitted again after [`begin_shutdown`].
+pub fn cancel_shutdown() {
+    SHUTTING_DOWN.store(false, Relaxed);
+}
+
+// Hands the callback back in an error if it can't be submitted at all right now.
+pub(crate) fn check_open<F>(func: F) -> Result<F, SubmitError<F>> {
+    if shutting_down() {
+        Err(SubmitError::ShuttingDown(func))
+    } else if !channels_open() {
+        Err(SubmitError::Disconnected(func))
+    } else {
+        Ok(func)
+    }
+}
+
+// Counts the callback towards the rate limit, handing it back if it's over. Take it back with uncount_submit if
+// it doesn't get sent after all.
+pub(crate) fn count_submit<F>(func: F) -> Result<F, SubmitError<F>> {
+    if SUBMITS_THIS_TICK.fetch_add(1, Relaxed) >= SUBMIT_RATE_LIMIT.load(Relaxed) {
+        uncount_submit();
+        return Err(SubmitError::RateLimited(func));
+    }
+    Ok(func)
+}
+
+pub(crate) fn submit_boxed(func: DeferredFunc) -> Result<(), SubmitError> {
+    let func = track(count_submit(check_open(func)?)?);
+    let result = match byond_callback_sender().try_send(func) {
+        Err(flume::TrySendError::Full(func)) if OVERFLOW.load(Relaxed) => {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                overflowed = super::with_overflow_receiver(|receiver| receiver.len()) + 1,
+                "callback queue full, sending to overflow"
+            );
+            overflow_sender().try_send(func)?;
+            Ok(())
+        }
+        Err(flume::TrySendError::Full(func)) if defer_allowed() => {
+            DEFERRED.with(|deferred| deferred.borrow_mut().push_back(func));
+            Ok(())
+        }
+        result => Ok(result?),
+    };
+    match &result {
+        Ok(()) => lifecycle::submitted(),
+        Err(_) => uncount_submit(),
+    }
+    result
+}
+
+/// Submits a callback that only runs if `datum` hasn't been qdel'd by the time it comes up, so a callback can't
+/// touch a datum that's gone. Skipped callbacks are counted in [`stale_callbacks`](super::stale_callbacks).
+/// This has to be called from the game thread, since that's the only place the datum can be looked at, e.g. from a hook:
+/// ```
+/// # use auxtools::*;
+/// #[hook("/datum/foo/proc/start_work")]
+/// fn _start_work() {
+///     let submitted = auxcallback::submit_while_alive(src, || {
+///         // only runs if src is still around
+///         Ok(Value::null())
+///     });
+///     if submitted.is_err() {
+///         return Err(runtime!("Could not queue the work"));
+///     }
+///     Ok(Value::null())
+/// }
+/// ```
+/// If it can't be queued, the callback you get back in the error will always count as stale.
+pub fn submit_while_alive(
+    datum: &Value,
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+) -> Result<(), SubmitError> {
+    let held = held::Held::new(datum.clone());
+    let id = held.id();
+    submit(move || match held.take() {
+        Some(datum) if !held::is_deleted(&datum) => func(),
+        _ => {
+            stats::record_stale();
+            Ok(Value::null())
+        }
+    })
+    .inspect_err(|_| {
+        held::take(id);
+    })
+}
+
+/// Same as [`submit`], but also tells you how many callbacks were queued ahead of it, for guessing how long it'll be
+/// until it runs. That's only a snapshot: other threads submit at the same time, and critical callbacks jump the
+/// line, so treat it as an estimate.
+pub fn submit_positioned(
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+) -> Result<usize, SubmitError> {
+    let func = check_open::<DeferredFunc>(Box::new(func))?;
+    let ahead = super::with_callback_receiver(|receiver| receiver.len())
+        + super::with_overflow_receiver(|receiver| receiver.len());
+    submit_boxed(func)?;
+    Ok(ahead)
+}
+
+// Failures from submit_logged off the game thread, waiting for the next processing call to report them.
+static DROPPED_MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Sends off a callback, and if it can't be, says so through `/proc/auxtools_stack_trace` instead of handing
+/// back an error. For when losing the odd callback is fine, as long as you hear about it.
+/// Off the game thread, the message waits until the next processing call.
+pub fn submit_logged(func: impl Fn() -> DMResult + Send + Sync + 'static) {
+    if let Err(e) = submit(func) {
+        let message = format!("Dropped a callback: {}", e);
+        if on_game_thread() {
+            super::errors::report_warning(&message);
+        } else {
+            DROPPED_MESSAGES.lock().unwrap().push(message);
+        }
+    }
+}
+
+pub(crate) fn report_dropped() {
+    let messages = std::mem::take(&mut *DROPPED_MESSAGES.lock().unwrap());
+    for message in messages {
+        super::errors::report_warning(&message);
+    }
+}
+
+// Held while checking and submitting in submit_if, so two of them can't both see room for one more.
+static SUBMIT_IF_LOCK: Mutex<()> = Mutex::new(());
+
+/// Only submits the callback if `cond` is true of how many callbacks are queued, like `|len| len < 50` to keep
+/// it to 50 at most. Returns whether it was submitted. Calls to this can't race each other between the check
+/// and the submit, but anything submitted some other way at the same time can still sneak in.
+pub fn submit_if(
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+    cond: impl Fn(usize) -> bool,
+) -> Result<bool, SubmitError> {
+    let func = check_open::<DeferredFunc>(Box::new(func))?;
+    let _lock = SUBMIT_IF_LOCK.lock().unwrap();
+    let len = super::with_callback_receiver(|receiver| receiver.len())
+        + super::with_overflow_receiver(|receiver| receiver.len());
+    if !cond(len) {
+        return Ok(false);
+    }
+    submit_boxed(func)?;
+    Ok(true)
+}
+
+/// Submits a group of callbacks that always run together, one after another in the same processing call.
+/// Time limits are only checked between callbacks, so the group is never split across ticks; the
+/// catch is it can run over a time limit by however long the whole group takes. If one of them errors,
+/// the rest are skipped.
+pub fn submit_transaction(funcs: Vec<DeferredFunc>) -> Result<(), SubmitError> {
+    submit(move || {
+        for func in &funcs {
+            func()?;
+        }
+        Ok(Value::null())
+    })
+}
+
+/// Sends off a callback, waiting for room if the queue is full instead of failing. Time spent waiting is
+/// counted in [`blocked_time`](super::blocked_time). The queue is only ever emptied on the game thread, so there
+/// this can't wait; it fails with [`SubmitError::Full`] like [`submit`] does. Overflow and the rate limit don't
+/// apply.
+pub fn submit_blocking(
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+) -> Result<(), SubmitError> {
+    let func = check_open::<DeferredFunc>(Box::new(func))?;
+    let sender = byond_callback_sender();
+    let func = match sender.try_send(track(func)) {
+        Err(flume::TrySendError::Full(func)) if !on_game_thread() => func,
+        result => return Ok(result.inspect(|_| lifecycle::submitted())?),
+    };
+    let started = Instant::now();
+    let result = sender.send(func);
+    stats::record_blocked(started.elapsed());
+    result
+        .inspect(|_| lifecycle::submitted())
+        .map_err(|e| SubmitError::Disconnected(e.into_inner()))
+}
+
+/// Sends off a callback, and if the queue's full, tries again up to `attempts` more times, sleeping for `backoff`
+/// in between. Rides out short bursts without blocking for as long as [`submit_blocking`] might. Don't call this
+/// from the game thread, where sleeping holds up the whole server; there it doesn't retry at all. Overflow and
+/// the rate limit don't apply.
+pub fn submit_with_send_retry(
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+    attempts: usize,
+    backoff: Duration,
+) -> Result<(), SubmitError> {
+    let mut func = track(check_open::<DeferredFunc>(Box::new(func))?);
+    let sender = byond_callback_sender();
+    let retries = if on_game_thread() { 0 } else { attempts };
+    for _ in 0..retries {
+        match sender.try_send(func) {
+            Err(flume::TrySendError::Full(unsent)) => func = unsent,
+            result => return Ok(result.inspect(|_| lifecycle::submitted())?),
+        }
+        std::thread::sleep(backoff);
+    }
+    Ok(sender.try_send(func).inspect(|_| lifecycle::submitted())?)
+}
+
+/// Submits every callback from `callbacks`, carrying on past any that can't be, and returns how many made it.
+/// Unlike [`submit_transaction`], they're ordinary separate callbacks.
+pub fn extend_callbacks(callbacks: impl IntoIterator<Item = DeferredFunc>) -> usize {
+    callbacks
+        .into_iter()
+        .map(submit_boxed)
+        .filter(Result::is_ok)
+        .count()
+}
+
+/// Sends off a callback whose result goes to `on_result` once it's been run, so whoever submitted it can deal
+/// with how it went. Errors go to the handler instead of `/proc/auxtools_stack_trace`.
+pub fn submit_with_handler(
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+    on_result: impl Fn(DMResult) + Send + Sync + 'static,
+) -> Result<(), SubmitError> {
+    submit(move || {
+        on_result(func());
+        Ok(Value::null())
+    })
+}
+
+/// Sends off a callback for a long job that can say how far along it is, by calling the function it's given
+/// with a number from 0 to 1. That goes to the proc set with [`set_progress_proc`](super::set_progress_proc).
+pub fn submit_progress(
+    func: impl Fn(&dyn Fn(f32)) -> DMResult + Send + Sync + 'static,
+) -> Result<(), SubmitError> {
+    submit(move || func(&super::alerts::progress))
+}
+
+/// Where the number from a callback sent with [`submit_number`] ends up once it's been run.
+#[derive(Clone, Debug, Default)]
+pub struct NumberResult(Arc<Mutex<Option<f32>>>);
+
+impl NumberResult {
+    /// The number, if the callback's been run and didn't fail.
+    pub fn get(&self) -> Option<f32> {
+        *self.0.lock().unwrap()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.get().is_some()
+    }
+}
+
+/// Sends off a callback that works out a number, giving back somewhere to pick it up from once it's been run,
+/// e.g. on a later tick. Unlike a `Value`, the number can be read from any thread. If the callback fails,
+/// the error's reported like usual and there's never a number.
+pub fn submit_number(
+    func: impl Fn() -> DMResult<f32> + Send + Sync + 'static,
+) -> Result<NumberResult, SubmitError> {
+    let result = NumberResult::default();
+    let slot = Arc::clone(&result.0);
+    submit(move || {
+        *slot.lock().unwrap() = Some(func()?);
+        Ok(Value::null())
+    })?;
+    Ok(result)
+}
+
+/// Sends off a callback that remembers where it was submitted from, and adds that to its error message if it
+/// fails, for tracking down callbacks that only go wrong long after whatever queued them. Capturing the backtrace
+/// is slow, so save this for callbacks you're actually chasing.
+#[cfg(feature = "backtrace")]
+pub fn submit_traced(
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+) -> Result<(), SubmitError> {
+    let backtrace = std::backtrace::Backtrace::force_capture();
+    submit(move || func().map_err(|e| runtime!("{}\nsubmitted from:\n{}", e.message, backtrace)))
+}
+
+/// Sends off a callback that counts as costing `cost` towards the budget of
+/// [`process_callbacks_cost_budget`](super::process_callbacks_cost_budget), rather than the usual 1.
+pub fn submit_with_cost(
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+    cost: u32,
+) -> Result<(), SubmitError> {
+    submit(move || {
+        LAST_COST.with(|last| last.set(Some(cost)));
+        func()
+    })
+}
+
+// What the callback that's just been run cost.
+pub(crate) fn take_cost() -> u64 {
+    LAST_COST.with(|last| last.take()).map_or(1, u64::from)
+}
+
+/// If called from the game thread, runs the function right away and gives back its result.
+/// Otherwise, it's sent off to be processed later, same as sending it yourself, and you get None.
+pub fn submit_or_run(
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+) -> Result<Option<DMResult>, SubmitError> {
+    if on_game_thread() {
+        Ok(Some(func()))
+    } else {
+        submit(func)?;
+        Ok(None)
+    }
+}
+
+/// Splits up a big job over `0..total` into callbacks that each handle `chunk` of it (the last one may get less),
+/// so it gets spread out over however many ticks it takes. If one of the submits fails, the chunks before it
+/// stay queued.
+/// Panics if `chunk` is zero, like [`slice::chunks`].
+pub fn submit_chunked(
+    total: usize,
+    chunk: usize,
+    f: impl Fn(Range<usize>) -> DMResult + Send + Sync + 'static,
+) -> Result<(), SubmitError> {
+    assert!(chunk != 0, "chunk size must be non-zero");
+    let f = Arc::new(f);
+    for range in chunk_ranges(total, chunk) {
+        let f = Arc::clone(&f);
+        submit(move || f(range.clone()))?;
+    }
+    Ok(())
+}
+
+// The ranges submit_chunked hands out, in order. The last one stops at total even if that's near usize::MAX.
+fn chunk_ranges(total: usize, chunk: usize) -> impl Iterator<Item = Range<usize>> {
+    (0..total)
+        .step_by(chunk)
+        .map(move |start| start..total.min(start.saturating_add(chunk)))
+}
+
+/// Sends off a callback that has to run, like cleanup. These go in their own unbounded queue that isn't rate limited,
+/// and get processed after everything else, in the time [`process_callbacks_reserved`] keeps aside for them.
+/// They're still taken after [`begin_shutdown`], so cleanup can be queued while shutting down; they're only turned
+/// away once the library's actually shut down.
+///
+/// [`process_callbacks_reserved`]: super::process_callbacks_reserved
+pub fn submit_critical(
+    func: impl Fn() -> DMResult + Send + Sync + 'static,
+) -> Result<(), SubmitError> {
+    // Checked before the sender's looked up, since that panics if the library isn't loaded.
+    if !channels_open() {
+        return Err(SubmitError::Disconnected(Box::new(func)));
+    }
+    let func = track(Box::new(func));
+    critical_sender().try_send(func)?;
+    lifecycle::submitted();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_cover_everything_once() {
+        for (total, chunk) in [(0, 3), (1, 3), (9, 3), (10, 3), (10, 20), (7, 1)] {
+            let mut covered = vec![0; total];
+            for range in chunk_ranges(total, chunk) {
+                assert!(!range.is_empty() && range.len() <= chunk);
+                for index in range {
+                    covered[index] += 1;
+                }
+            }
+            assert!(
+                covered.iter().all(|&count| count == 1),
+                "{} in chunks of {}",
+                total,
+                chunk
+            );
+        }
+    }
+
+    #[test]
+    fn chunks_near_the_top_dont_overflow() {
+        let ranges: Vec<_> = chunk_ranges(usize::MAX, usize::MAX / 2 + 1).collect();
+        assert_eq!(
+            ranges,
+            vec![0..usize::MAX / 2 + 1, usize::MAX / 2 + 1..usize::MAX]
+        );
+    }
+}
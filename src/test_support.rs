@@ -0,0 +1,45 @@
+//! Lets tests of code that defers work run without BYOND. Everything else in this crate goes through `Value`s,
+//! which can't exist outside the game, so this keeps a separate queue of plain Rust callbacks that report errors
+//! as strings. Enabled with the `test_support` feature.
+//!
+//! ```
+//! use auxcallback::test_support::{push_test_callback, run_test_callbacks};
+//!
+//! push_test_callback(|| Ok(()));
+//! push_test_callback(|| Err("couldn't find the turf".to_string()));
+//! assert_eq!(
+//!     run_test_callbacks(),
+//!     vec![Ok(()), Err("couldn't find the turf".to_string())]
+//! );
+//! assert!(run_test_callbacks().is_empty());
+//! ```
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+type TestCallback = Box<dyn FnOnce() -> Result<(), String> + Send>;
+
+static TEST_CALLBACKS: Mutex<VecDeque<TestCallback>> = Mutex::new(VecDeque::new());
+
+/// Queues up a callback for [`run_test_callbacks`].
+pub fn push_test_callback(func: impl FnOnce() -> Result<(), String> + Send + 'static) {
+    TEST_CALLBACKS.lock().unwrap().push_back(Box::new(func));
+}
+
+/// How many callbacks are waiting for [`run_test_callbacks`].
+pub fn pending_test_callbacks() -> usize {
+    TEST_CALLBACKS.lock().unwrap().len()
+}
+
+/// Runs every queued test callback in order, including any they queue themselves, and gives back their results.
+pub fn run_test_callbacks() -> Vec<Result<(), String>> {
+    let mut results = Vec::new();
+    loop {
+        // Not held while the callback runs, so it can queue more.
+        let next = TEST_CALLBACKS.lock().unwrap().pop_front();
+        match next {
+            Some(func) => results.push(func()),
+            None => return results,
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+use std::sync::Mutex;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// In nanoseconds; 0 means it's off.
+static LIMIT: AtomicU64 = AtomicU64::new(0);
+static WATCHER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+// The callback that's running right now, if the watchdog's watching.
+static CURRENT: Mutex<Option<Running>> = Mutex::new(None);
+static NEXT_NUMBER: AtomicU64 = AtomicU64::new(0);
+static REPORTS: Mutex<Vec<WatchdogReport>> = Mutex::new(Vec::new());
+// Older reports are thrown out past this, so a server that's always slow doesn't fill up memory with them.
+const MAX_REPORTS: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Running {
+    // In nanoseconds since the unix epoch.
+    started: u64,
+    // Which callback it is, so the same one isn't reported twice.
+    number: u64,
+}
+
+/// A callback that was caught running for longer than the watchdog allows.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchdogReport {
+    pub started: SystemTime,
+    /// How long it had been running when the watchdog noticed. It may well have carried on after that.
+    pub running_for: Duration,
+}
+
+/// Starts a watchdog thread that keeps an eye on callbacks as they run, and records a [`WatchdogReport`]
+/// (and warns about it through `tracing`, with the `tracing` feature) for anything that runs longer than the
+/// limit. It can't stop the callback, since there's no safe way to interrupt BYOND, but if the server hangs
+/// this at least tells you it was a callback. None, the default, turns it off, as does the library shutting down.
+pub fn set_watchdog(limit: Option<Duration>) {
+    let nanos = match limit {
+        Some(limit) => (limit.as_nanos().min(u64::MAX as u128) as u64).max(1),
+        None => return stop(),
+    };
+    LIMIT.store(nanos, Relaxed);
+    let mut watcher = WATCHER.lock().unwrap();
+    if watcher.is_none() {
+        *watcher = std::thread::Builder::new().spawn(watch).ok();
+    }
+}
+
+// Turns the watchdog off and waits for its thread to finish.
+pub(crate) fn stop() {
+    LIMIT.store(0, Relaxed);
+    let watcher = WATCHER.lock().unwrap().take();
+    if let Some(handle) = watcher {
+        handle.thread().unpark();
+        let _ = handle.join();
+    }
+}
+
+/// Everything the watchdog has caught so far, oldest first, up to the last hundred.
+pub fn watchdog_reports() -> Vec<WatchdogReport> {
+    REPORTS.lock().unwrap().clone()
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |since| since.as_nanos() as u64)
+}
+
+/// Whatever was running before a callback started, which is only something when callbacks are run from inside
+/// other callbacks. Hand it back to [`callback_finished`] so the watchdog goes back to watching that one.
+/// The outer None means the watchdog was off, so there's nothing to put back.
+pub(crate) struct Outer(Option<Option<Running>>);
+
+pub(crate) fn callback_started() -> Outer {
+    if LIMIT.load(Relaxed) == 0 {
+        return Outer(None);
+    }
+    let running = Running {
+        started: now_nanos(),
+        number: NEXT_NUMBER.fetch_add(1, Relaxed),
+    };
+    Outer(Some(CURRENT.lock().unwrap().replace(running)))
+}
+
+pub(crate) fn callback_finished(outer: Outer) {
+    if let Some(outer) = outer.0 {
+        *CURRENT.lock().unwrap() = outer;
+    }
+}
+
+fn watch() {
+    let mut last_reported = None;
+    loop {
+        let limit = LIMIT.load(Relaxed);
+        if limit == 0 {
+            return;
+        }
+        std::thread::park_timeout(Duration::from_nanos(limit / 2).max(Duration::from_millis(1)));
+        let running = match *CURRENT.lock().unwrap() {
+            Some(running) if last_reported != Some(running.number) => running,
+            _ => continue,
+        };
+        let running_for = Duration::from_nanos(now_nanos().saturating_sub(running.started));
+        if running_for.as_nanos() > limit as u128 {
+            last_reported = Some(running.number);
+            let report = WatchdogReport {
+                started: UNIX_EPOCH + Duration::from_nanos(running.started),
+                running_for,
+            };
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                running_for_ms = report.running_for.as_millis() as u64,
+                "callback running too long"
+            );
+            let mut reports = REPORTS.lock().unwrap();
+            if reports.len() >= MAX_REPORTS {
+                reports.remove(0);
+            }
+            reports.push(report);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_callbacks_hand_back_to_the_outer_one() {
+        LIMIT.store(Duration::from_secs(1).as_nanos() as u64, Relaxed);
+        let outer = callback_started();
+        let running = CURRENT.lock().unwrap().unwrap();
+
+        let inner = callback_started();
+        assert_ne!(CURRENT.lock().unwrap().unwrap().number, running.number);
+        callback_finished(inner);
+        assert_eq!(*CURRENT.lock().unwrap(), Some(running));
+
+        callback_finished(outer);
+        assert_eq!(*CURRENT.lock().unwrap(), None);
+        LIMIT.store(0, Relaxed);
+    }
+}
@@ -0,0 +1,534 @@
+//! A small binary format for asking for callbacks from outside the process, e.g. a helper program talking
+//! over a socket. Closures can't be sent, so instead callbacks are registered ahead of time under an opcode
+//! with [`register_opcode`], and a message just says which opcode to run and what numbers to give it.
+//!
+//! A message is the opcode as a little endian u16, then the number of arguments as a little endian u32,
+//! then each argument as a little endian f32.
+//!
+//! Since they're just data, callbacks submitted this way can also be saved and put back across a reload of the
+//! library with [`serialize_pending_opcodes`] and [`restore_pending_opcodes`], or spilled to disk when the queue's
+//! full with [`set_spill_path`]. Ordinary closures can't.
+
+use auxtools::Value;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering::Relaxed};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use super::submit::{submit_boxed, SubmitError};
+use super::DeferredFunc;
+
+type OpcodeFactory = dyn Fn(&[f32]) -> DeferredFunc + Send + Sync;
+
+static OPCODES: Mutex<BTreeMap<u16, Arc<OpcodeFactory>>> = Mutex::new(BTreeMap::new());
+
+// Messages whose callbacks are still queued, in the order they were submitted.
+static PENDING: Mutex<BTreeMap<u64, WireMessage>> = Mutex::new(BTreeMap::new());
+static NEXT_PENDING: AtomicU64 = AtomicU64::new(0);
+
+// Also locked while the spill file's being read or written.
+static SPILL_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+// The thread reloading spilled messages, and what tells it to stop.
+static SPILL_THREAD: Mutex<Option<(Arc<AtomicBool>, JoinHandle<()>)>> = Mutex::new(None);
+
+// Keeps a message in PENDING for as long as it's alive. Put in a callback, that's until it's been run or dropped.
+struct PendingMessage(u64);
+
+impl PendingMessage {
+    fn new(message: WireMessage) -> Self {
+        let id = NEXT_PENDING.fetch_add(1, Relaxed);
+        PENDING.lock().unwrap().insert(id, message);
+        Self(id)
+    }
+}
+
+impl PendingMessage {
+    // Whether its message has been handed off with export_backlog, so it's up to someone else to run it now.
+    fn exported(&self) -> bool {
+        !PENDING.lock().unwrap().contains_key(&self.0)
+    }
+}
+
+impl Drop for PendingMessage {
+    fn drop(&mut self) {
+        PENDING.lock().unwrap().remove(&self.0);
+    }
+}
+
+pub(crate) fn pending_messages() -> usize {
+    PENDING.lock().unwrap().len()
+}
+
+/// Asks for whatever callback is registered under the opcode to be run with the given arguments.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WireMessage {
+    pub opcode: u16,
+    pub args: Vec<f32>,
+}
+
+impl WireMessage {
+    pub fn new(opcode: u16, args: Vec<f32>) -> Self {
+        Self { opcode, args }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(6 + self.args.len() * 4);
+        bytes.extend_from_slice(&self.opcode.to_le_bytes());
+        bytes.extend_from_slice(&(self.args.len() as u32).to_le_bytes());
+        for arg in &self.args {
+            bytes.extend_from_slice(&arg.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Reads a message off the start of the bytes, giving back the message and how many bytes it took up,
+    /// so several can be read out of one buffer.
+    pub fn decode(bytes: &[u8]) -> Result<(Self, usize), WireError> {
+        if bytes.len() < 6 {
+            return Err(WireError::Truncated);
+        }
+        let opcode = u16::from_le_bytes([bytes[0], bytes[1]]);
+        let arg_count = u32::from_le_bytes([bytes[2], bytes[3], bytes[4], bytes[5]]) as usize;
+        let len = arg_count
+            .checked_mul(4)
+            .and_then(|arg_len| arg_len.checked_add(6))
+            .ok_or(WireError::Truncated)?;
+        if bytes.len() < len {
+            return Err(WireError::Truncated);
+        }
+        let args = bytes[6..len]
+            .chunks_exact(4)
+            .map(|arg| f32::from_le_bytes([arg[0], arg[1], arg[2], arg[3]]))
+            .collect();
+        Ok((Self { opcode, args }, len))
+    }
+}
+
+/// Why a wire message couldn't be turned into a callback.
+#[derive(Debug)]
+pub enum WireError {
+    /// The message was cut off partway through.
+    Truncated,
+    /// Nothing's registered under the opcode.
+    UnknownOpcode(u16),
+    /// The callback was made, but couldn't be submitted.
+    Submit(SubmitError),
+    /// The spill file couldn't be read or written.
+    Io(io::Error),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated => f.write_str("wire message is truncated"),
+            Self::UnknownOpcode(opcode) => {
+                write!(f, "no callback registered for opcode {}", opcode)
+            }
+            Self::Submit(err) => err.fmt(f),
+            Self::Io(err) => write!(f, "couldn't use the spill file: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+impl From<SubmitError> for WireError {
+    fn from(err: SubmitError) -> Self {
+        Self::Submit(err)
+    }
+}
+
+impl From<io::Error> for WireError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Registers what to run for an opcode: the factory gets the message's arguments and makes the callback.
+/// Registering the same opcode again replaces it.
+pub fn register_opcode(
+    opcode: u16,
+    factory: impl Fn(&[f32]) -> DeferredFunc + Send + Sync + 'static,
+) {
+    OPCODES.lock().unwrap().insert(opcode, Arc::new(factory));
+}
+
+/// Makes the callback a message asks for, without submitting it.
+pub fn make_callback(message: &WireMessage) -> Result<DeferredFunc, WireError> {
+    let factory = OPCODES
+        .lock()
+        .unwrap()
+        .get(&message.opcode)
+        .cloned()
+        .ok_or(WireError::UnknownOpcode(message.opcode))?;
+    Ok(factory(&message.args))
+}
+
+/// Decodes a single message and submits the callback it asks for.
+pub fn submit_wire(bytes: &[u8]) -> Result<(), WireError> {
+    let (message, _) = WireMessage::decode(bytes)?;
+    submit_message(&message)
+}
+
+/// Submits the callback a message asks for. If the queue's full and there's a spill path set, the message is
+/// written there instead, to be submitted once there's room.
+pub fn submit_message(message: &WireMessage) -> Result<(), WireError> {
+    match submit_unspilled(message) {
+        Err(WireError::Submit(SubmitError::Full(_))) if spill(message)? => Ok(()),
+        result => result,
+    }
+}
+
+fn submit_unspilled(message: &WireMessage) -> Result<(), WireError> {
+    let callback = make_callback(message)?;
+    let pending = PendingMessage::new(message.clone());
+    submit_boxed(Box::new(move || {
+        if pending.exported() {
+            return Ok(Value::null());
+        }
+        callback()
+    }))?;
+    Ok(())
+}
+
+/// Encodes every message whose callback is still queued, one after another, oldest first. Call this before
+/// the library's reloaded and hand the bytes to [`restore_pending_opcodes`] afterwards to pick back up where
+/// it left off. Only callbacks submitted through this module are included.
+pub fn serialize_pending_opcodes() -> Vec<u8> {
+    PENDING
+        .lock()
+        .unwrap()
+        .values()
+        .flat_map(|message| message.encode())
+        .collect()
+}
+
+/// Like [`serialize_pending_opcodes`], but hands the messages over rather than copying them: their callbacks are
+/// skipped here from now on, so another instance of the library can take them up with [`restore_pending_opcodes`]
+/// without anything running twice. Also gives back how many other callbacks were queued, which can't be
+/// handed over and are left where they are.
+pub fn export_backlog() -> (Vec<u8>, usize) {
+    let exported = std::mem::take(&mut *PENDING.lock().unwrap());
+    let bytes = exported
+        .values()
+        .flat_map(|message| message.encode())
+        .collect();
+    let queued = if super::channels_open() {
+        super::pending_count()
+    } else {
+        0
+    };
+    (bytes, queued.saturating_sub(exported.len()))
+}
+
+/// Submits every message in bytes from [`serialize_pending_opcodes`], returning how many there were.
+/// The opcodes need to be registered again first. Stops at the first one that fails.
+pub fn restore_pending_opcodes(mut bytes: &[u8]) -> Result<usize, WireError> {
+    let mut restored = 0;
+    while !bytes.is_empty() {
+        let (message, len) = WireMessage::decode(bytes)?;
+        submit_message(&message)?;
+        bytes = &bytes[len..];
+        restored += 1;
+    }
+    Ok(restored)
+}
+
+/// Writes messages that don't fit in a full queue to the file at `path`, rather than failing to submit them,
+/// and starts a thread that puts them back in the queue as room frees up. Only messages go there, since
+/// they're just data; ordinary callbacks still fail when the queue's full. None, the default, turns it off,
+/// leaving anything already spilled in the file for next time. It's also turned off when the library shuts down,
+/// so set it again whenever it's loaded.
+pub fn set_spill_path(path: Option<PathBuf>) {
+    if path.is_none() {
+        stop();
+        return;
+    }
+    *SPILL_PATH.lock().unwrap() = path;
+    let mut thread = SPILL_THREAD.lock().unwrap();
+    if thread.is_none() {
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread_stopping = Arc::clone(&stopping);
+        if let Ok(handle) =
+            std::thread::Builder::new().spawn(move || reload_in_background(&thread_stopping))
+        {
+            *thread = Some((stopping, handle));
+        }
+    }
+}
+
+// Turns spilling off and waits for the reload thread to finish, so it's gone before the queues are.
+pub(crate) fn stop() {
+    *SPILL_PATH.lock().unwrap() = None;
+    let thread = SPILL_THREAD.lock().unwrap().take();
+    if let Some((stopping, handle)) = thread {
+        stopping.store(true, Relaxed);
+        handle.thread().unpark();
+        let _ = handle.join();
+    }
+}
+
+// Whether it was spilled, which it isn't if there's nowhere to spill to.
+fn spill(message: &WireMessage) -> Result<bool, WireError> {
+    let path = SPILL_PATH.lock().unwrap();
+    match path.as_ref() {
+        Some(path) => {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?
+                .write_all(&message.encode())?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Submits as many spilled messages as there's room for, oldest first, returning how many went in.
+/// The thread started by [`set_spill_path`] calls this on its own, but it can be called sooner.
+/// A message that can't ever be submitted, like one whose opcode isn't registered, is taken out of the file
+/// so the rest can go in, and the first such error is given back once they have. Register the opcodes before
+/// setting the spill path, so nothing left over from last time is thrown out before they're there.
+pub fn reload_spilled() -> Result<usize, WireError> {
+    let path = SPILL_PATH.lock().unwrap();
+    let path = match path.as_ref() {
+        Some(path) => path,
+        None => return Ok(0),
+    };
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let mut rest = &bytes[..];
+    let mut reloaded = 0;
+    let mut result = Ok(());
+    while !rest.is_empty() {
+        let (message, len) = match WireMessage::decode(rest) {
+            Ok(decoded) => decoded,
+            Err(err) => {
+                // Whatever's left can't be read, so there's no point keeping it around.
+                rest = &[];
+                result = Err(err);
+                break;
+            }
+        };
+        match submit_unspilled(&message) {
+            Ok(()) => reloaded += 1,
+            Err(WireError::Submit(SubmitError::Full(_))) => break,
+            // Not taking callbacks right now, so it and everything after it waits for next time.
+            Err(err @ WireError::Submit(_)) => {
+                result = result.and(Err(err));
+                break;
+            }
+            // This one can never go in, so it's dropped rather than holding up everything behind it.
+            Err(err) => result = result.and(Err(err)),
+        }
+        rest = &rest[len..];
+    }
+    if rest.is_empty() {
+        fs::remove_file(path)?;
+    } else {
+        fs::write(path, rest)?;
+    }
+    result.map(|_| reloaded)
+}
+
+fn reload_in_background(stopping: &AtomicBool) {
+    loop {
+        std::thread::park_timeout(Duration::from_millis(100));
+        if stopping.load(Relaxed) {
+            return;
+        }
+        if super::channels_open() {
+            let _ = reload_spilled();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // PENDING is shared, so tests that look at it take turns.
+    static PENDING_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn round_trip() {
+        let message = WireMessage::new(7, vec![1.5, -2.0, f32::MAX]);
+        let bytes = message.encode();
+        assert_eq!(bytes.len(), 6 + 3 * 4);
+        assert_eq!(WireMessage::decode(&bytes).unwrap(), (message, bytes.len()));
+    }
+
+    #[test]
+    fn decodes_one_message_at_a_time() {
+        let first = WireMessage::new(1, vec![1.0]);
+        let second = WireMessage::new(2, vec![]);
+        let mut bytes = first.encode();
+        bytes.extend(second.encode());
+        let (decoded, len) = WireMessage::decode(&bytes).unwrap();
+        assert_eq!(decoded, first);
+        assert_eq!(WireMessage::decode(&bytes[len..]).unwrap(), (second, 6));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = WireMessage::new(3, vec![1.0, 2.0]).encode();
+        for len in 0..bytes.len() {
+            assert!(matches!(
+                WireMessage::decode(&bytes[..len]),
+                Err(WireError::Truncated)
+            ));
+        }
+    }
+
+    #[test]
+    fn huge_arg_count() {
+        let mut bytes = 3u16.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&1.0f32.to_le_bytes());
+        assert!(matches!(
+            WireMessage::decode(&bytes),
+            Err(WireError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn dispatches_by_opcode() {
+        static SEEN: Mutex<Vec<f32>> = Mutex::new(Vec::new());
+        register_opcode(1000, |args| {
+            SEEN.lock().unwrap().extend_from_slice(args);
+            Box::new(|| Err(auxtools::runtime!("ran 1000")))
+        });
+        register_opcode(1001, |_| Box::new(|| Err(auxtools::runtime!("ran 1001"))));
+        let callback = make_callback(&WireMessage::new(1000, vec![4.0, 5.0])).unwrap();
+        assert_eq!(*SEEN.lock().unwrap(), [4.0, 5.0]);
+        assert_eq!(callback().err().unwrap().message, "ran 1000");
+        assert!(matches!(
+            make_callback(&WireMessage::new(1002, vec![])),
+            Err(WireError::UnknownOpcode(1002))
+        ));
+    }
+
+    #[test]
+    fn reregistering_replaces() {
+        register_opcode(1010, |_| Box::new(|| Err(auxtools::runtime!("old"))));
+        register_opcode(1010, |_| Box::new(|| Err(auxtools::runtime!("new"))));
+        let callback = make_callback(&WireMessage::new(1010, vec![])).unwrap();
+        assert_eq!(callback().err().unwrap().message, "new");
+    }
+
+    #[test]
+    fn serializes_pending_in_order() {
+        let _lock = PENDING_LOCK.lock().unwrap();
+        let first = WireMessage::new(20, vec![1.0]);
+        let second = WireMessage::new(21, vec![2.0, 3.0]);
+        let third = WireMessage::new(22, vec![]);
+        let pending = [
+            PendingMessage::new(first.clone()),
+            PendingMessage::new(second.clone()),
+            PendingMessage::new(third.clone()),
+        ];
+        let mut expected = first.encode();
+        expected.extend(second.encode());
+        expected.extend(third.encode());
+        assert_eq!(serialize_pending_opcodes(), expected);
+        assert_eq!(pending_messages(), 3);
+
+        // Once a callback's run or dropped, its message goes with it.
+        let [first, _, third] = pending;
+        drop(first);
+        drop(third);
+        assert_eq!(serialize_pending_opcodes(), second.encode());
+    }
+
+    #[test]
+    fn restore_stops_at_first_failure() {
+        let _lock = PENDING_LOCK.lock().unwrap();
+        register_opcode(30, |_| Box::new(|| Err(auxtools::runtime!("ran 30"))));
+        assert_eq!(restore_pending_opcodes(&[]).unwrap(), 0);
+        assert!(matches!(
+            restore_pending_opcodes(&WireMessage::new(31, vec![]).encode()),
+            Err(WireError::UnknownOpcode(31))
+        ));
+        assert!(matches!(
+            restore_pending_opcodes(&WireMessage::new(30, vec![1.0]).encode()[..7]),
+            Err(WireError::Truncated)
+        ));
+        // The queue isn't open outside of BYOND, so nothing can be submitted, and nothing's left behind pending.
+        assert!(matches!(
+            restore_pending_opcodes(&WireMessage::new(30, vec![1.0]).encode()),
+            Err(WireError::Submit(SubmitError::Disconnected(_)))
+        ));
+        assert_eq!(pending_messages(), 0);
+    }
+
+    #[test]
+    fn export_hands_messages_over() {
+        let _lock = PENDING_LOCK.lock().unwrap();
+        let first = WireMessage::new(40, vec![1.0]);
+        let second = WireMessage::new(41, vec![2.0]);
+        let pending = [
+            PendingMessage::new(first.clone()),
+            PendingMessage::new(second.clone()),
+        ];
+        let mut expected = first.encode();
+        expected.extend(second.encode());
+        assert_eq!(export_backlog(), (expected, 0));
+        assert!(pending.iter().all(PendingMessage::exported));
+        // Exporting again doesn't hand the same messages over twice.
+        assert_eq!(export_backlog(), (Vec::new(), 0));
+        assert_eq!(serialize_pending_opcodes(), Vec::<u8>::new());
+        drop(pending);
+        assert_eq!(pending_messages(), 0);
+
+        let later = PendingMessage::new(first);
+        assert!(!later.exported());
+    }
+
+    #[test]
+    fn reload_skips_what_cant_go_in() {
+        let _lock = PENDING_LOCK.lock().unwrap();
+        let path = std::env::temp_dir().join(format!("auxcallback-spill-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        register_opcode(50, |_| Box::new(|| Err(auxtools::runtime!("ran 50"))));
+        let unknown = WireMessage::new(51, vec![1.0]);
+        let known = WireMessage::new(50, vec![2.0]);
+
+        assert!(!spill(&unknown).unwrap());
+        *SPILL_PATH.lock().unwrap() = Some(path.clone());
+        assert!(spill(&unknown).unwrap());
+        assert!(spill(&known).unwrap());
+        let mut spilled = unknown.encode();
+        spilled.extend(known.encode());
+        assert_eq!(fs::read(&path).unwrap(), spilled);
+
+        // The unknown opcode is dropped, but the queue isn't open outside of BYOND, so the other one stays.
+        assert!(matches!(
+            reload_spilled(),
+            Err(WireError::UnknownOpcode(51))
+        ));
+        assert_eq!(fs::read(&path).unwrap(), known.encode());
+
+        fs::write(&path, unknown.encode()).unwrap();
+        assert!(matches!(
+            reload_spilled(),
+            Err(WireError::UnknownOpcode(51))
+        ));
+        assert!(!path.exists());
+        assert_eq!(reload_spilled().unwrap(), 0);
+
+        // Anything after a message that's cut off can't be read, so it's all thrown out.
+        fs::write(&path, &known.encode()[..7]).unwrap();
+        assert!(matches!(reload_spilled(), Err(WireError::Truncated)));
+        assert!(!path.exists());
+        *SPILL_PATH.lock().unwrap() = None;
+    }
+}